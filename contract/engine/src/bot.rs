@@ -0,0 +1,89 @@
+//! Opponent played by a program: a fixed or seeded army and greedy moves over legal hop chains.
+
+use crate::Game;
+
+/// number of units in a seeded bot army
+const BOT_ARMY_SIZE: usize = 3;
+
+/// Army of the second player as `place_units` takes it. Without a seed it is an orc, a skeleton and a mage
+/// next to the right edge, with a seed unit types and cells on the second player's half are picked randomly.
+/// The desk must be at least 4x2.
+pub fn bot_army(width: i8, height: i8, seed: Option<u64>) -> Vec<(i8, i8, i8)> {
+    let Some(seed) = seed else {
+        return vec![(1, width - 2, 0), (2, width - 2, height - 1), (0, width - 1, height / 2)];
+    };
+    // xorshift64, the same seed always gives the same army
+    let mut state = seed.max(1);
+    let mut below = |n: usize| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % n as u64) as usize
+    };
+    let mut cells: Vec<(i8, i8)> = (width - width / 2..width).flat_map(|x| (0..height).map(move |y| (x, y))).collect();
+    (0..BOT_ARMY_SIZE).map(|_| {
+        let (x, y) = cells.swap_remove(below(cells.len()));
+        (below(3) as i8, x, y)
+    }).collect()
+}
+
+impl<P: Clone + PartialEq> Game<P> {
+    /// HP the move takes from the enemy minus HP it costs own units, `f32::MAX` when it wins the game,
+    /// `None` when the move is not legal
+    pub fn score_move(&self, unit_id: i8, moves: &[(i8, i8)], player: &P) -> Option<f32> {
+        let mut after = self.clone();
+        let outcome = after.make_move(unit_id, moves.to_vec(), player.clone()).ok()?;
+        if outcome.winner.as_ref() == Some(player) {
+            return Some(f32::MAX);
+        }
+        Some(outcome.damaged_units.iter().map(|damaged| {
+            let lost = damaged.hp_before - damaged.hp_after;
+            if self.owner_of(damaged.unit_id).as_ref() == Some(player) { -lost } else { lost }
+        }).sum())
+    }
+
+    /// Best scored move of the player among at most `limit` legal moves, longer hop chains are
+    /// scored first and ties go to the earlier move. `limit` bounds the work, every scored move is
+    /// played on a copy of the game.
+    pub fn greedy_move(&self, player: &P, limit: usize) -> Option<(i8, Vec<(i8, i8)>)> {
+        let mut moves = self.legal_moves_of(player);
+        moves.sort_by_key(|(_, chain)| std::cmp::Reverse(chain.len()));
+        let mut best = None;
+        let mut best_score = f32::MIN;
+        for (unit_id, chain) in moves.into_iter().take(limit) {
+            if let Some(score) = self.score_move(unit_id, &chain, player) {
+                if best.is_none() || score > best_score {
+                    best_score = score;
+                    best = Some((unit_id, chain));
+                }
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bot_places_its_army_and_plays_greedy_moves() {
+        assert_eq!(bot_army(7, 4, None), vec![(1, 5, 0), (2, 5, 3), (0, 6, 2)]);
+        let seeded = bot_army(7, 4, Some(42));
+        assert_eq!(seeded, bot_army(7, 4, Some(42)));
+        assert_ne!(seeded, bot_army(7, 4, Some(43)));
+        assert!(seeded.iter().all(|&(unit_type, x, y)| (0..3).contains(&unit_type) && (4..7).contains(&x) && (0..4).contains(&y)));
+
+        let mut game: Game<u8> = Game::new(1, 7, 4).unwrap();
+        game.join(2).unwrap();
+        game.place_units(bot_army(7, 4, None), 2).unwrap();
+        game.place_units(vec![(1, 2, 0), (0, 2, 1)], 1).unwrap();
+        game.make_move(4, vec![(2, 1), (3, 1)], 1).unwrap();
+        let (unit_id, chain) = game.greedy_move(&2, 100).unwrap();
+        let best = game.score_move(unit_id, &chain, &2).unwrap();
+        let scores: Vec<f32> = game.legal_moves_of(&2).iter().map(|(id, c)| game.score_move(*id, c, &2).unwrap()).collect();
+        assert!(best > 0.0 && scores.iter().all(|score| *score <= best));
+        assert!(game.greedy_move(&2, 1).is_some());
+        assert_eq!(game.greedy_move(&2, 0), None);
+    }
+}
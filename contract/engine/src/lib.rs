@@ -0,0 +1,937 @@
+//! Rules of HopChain: desk, units, placement and moves of a battle between two players.
+//! Players can be identified by any type, the contract uses account ids.
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+mod bot;
+mod notation;
+pub use bot::bot_army;
+pub use notation::{format_square, parse_square, Notation, NotationError, Record};
+
+/// desk cells are indexed with i8 and stored desk marks occupied cells in a u64
+pub const MAX_BOARD_CELLS: i8 = 64;
+
+const HOP_DIRECTIONS: [(i8, i8); 4] = [(1, 0), (-1, 0), (0, 1), (0, -1)];
+
+/// Every way a join, placement or move can break the rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameError {
+    NotYourBattle,
+    NotYourTurn,
+    NotYourUnit,
+    IllegalHop,
+    CellOccupied,
+    OutOfBounds,
+    WrongState,
+    UnknownUnitType,
+    UnitNotFound,
+    BattleFull,
+    TooManyUnits,
+    PlacementNotAllowed,
+    /// desk needs at least one cell and at most `MAX_BOARD_CELLS`, the bitmask of occupied cells has 64 bits
+    InvalidDeskSize,
+}
+
+#[derive(Copy, Clone, BorshDeserialize, BorshSerialize, PartialEq, PartialOrd, Eq, Ord, Serialize)]
+pub struct Vector2 {
+    pub x: i8,
+    pub y: i8,
+    /// index of the cell on the desk
+    pub id: i8,
+}
+
+impl Vector2 {
+    pub fn new(x_position: i8, y_position: i8, _id: i8) -> Vector2 {
+        Vector2{x: x_position, y: y_position, id: _id}
+    }
+}
+#[derive(Copy, Clone, BorshDeserialize, BorshSerialize, Serialize)]
+pub enum UnitType {
+    Orc,
+    Mage,
+    Skeleton
+}
+
+#[derive(Clone, BorshSerialize, BorshDeserialize, Copy, Serialize)]
+pub struct Unit {
+    pub unit_type: UnitType,
+    pub level: i8,
+    pub stats: UnitStats,
+    pub current_position: Vector2,
+    pub unit_id: i8,
+}
+
+impl Unit {
+    pub fn new(unit_level: i8, _unit_type: UnitType, id: i8) -> Self {
+        Self {
+            level: unit_level,
+            stats: UnitStats::new(unit_level, _unit_type),
+            unit_type: _unit_type,
+            current_position: Vector2 { x: 0, y: 0, id: -1 },
+            unit_id: id
+        }
+    }
+
+    pub fn get_unit_type_by_id(id: i8) -> Result<UnitType, GameError> {
+        match id {
+            0 => Ok(UnitType::Mage),
+            1 => Ok(UnitType::Orc),
+            2 => Ok(UnitType::Skeleton),
+            _ => Err(GameError::UnknownUnitType)
+        }
+    }
+
+    pub fn get_id_by_unit_type(unit_type: UnitType) -> i8 {
+        match unit_type {
+            UnitType::Mage => 0,
+            UnitType::Orc => 1,
+            UnitType::Skeleton => 2
+        }
+    }
+}
+
+#[derive(Clone, BorshSerialize, BorshDeserialize, Copy, Serialize, Debug)]
+pub struct UnitStats {
+    pub hp: f32,
+    pub damage: f32,
+    pub max_hp: f32,
+}
+
+impl UnitStats {
+    pub fn new(unit_level: i8, unit_type: UnitType) -> UnitStats {
+        UnitStats {
+            hp: UnitStats::get_unit_hp_by_level(unit_level, unit_type),
+            damage: UnitStats::get_unit_damage_by_level(unit_level, unit_type),
+            max_hp: UnitStats::get_unit_hp_by_level(unit_level, unit_type),
+        }
+    }
+    
+    /// returs max hp of unit based on it's level
+    /// # Usage 
+    /// * 'level' - level of the Unit
+    /// * 'unit_type' - Orc, Skeleton or Mage
+    fn get_unit_hp_by_level(level: i8, unit_type: UnitType) -> f32 {
+            match unit_type {
+                UnitType::Mage => 12.0 + 3.0*(level as f32-1.0),
+                UnitType::Orc => match level {
+                    1 => 40.0,
+                    2 => 42.0,
+                    3 => 48.0,
+                    4 => 56.0,
+                    5 => 64.0,
+                    _ => 0.0
+                }
+                UnitType::Skeleton => match level {
+                    1 => 25.0,
+                    2 => 27.0,
+                    3 => 30.0,
+                    4 => 35.0,
+                    5 => 40.0,
+                    _ => 0.0
+                }
+            }
+        }
+
+    /// returs damage of unit based on it's level
+    /// # Usage 
+    /// * 'level' - level of the Unit
+    /// * 'unit_type' - Orc, Skeleton or Mage
+    fn get_unit_damage_by_level(level: i8, unit_type: UnitType) -> f32 {
+        match unit_type {
+            UnitType::Mage => 8.0 + 2.0*(level as f32-1.0),
+            UnitType::Orc => match level {
+                1 => 4.0,
+                2 => 6.0,
+                3 => 6.0,
+                4 => 8.0,
+                5 => 8.0,
+                _ => 0.0
+            }
+            UnitType::Skeleton => 10.0 + 2.0*(level as f32-1.0)
+        }
+    }
+}
+
+///
+/// Implemetation of cell on a board, must contain unit which is on a cell right now
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Serialize)]
+pub struct Cell {
+    pub unit: Option<Unit>,
+    pub position: Vector2,
+}
+
+impl Cell {
+    pub fn new(x:i8, y: i8, id:i8) -> Self {
+        Cell{unit: None, position: Vector2::new(x,y, id)}
+    }
+}
+
+///
+/// Implementation of a desk in a game, contrains a map of all cells on a board.
+/// Cells are stored row by row, cell at (x, y) is at `y * x_range + x`.
+/// In storage the desk is only its size, a bitmask of occupied cells and the units on them.
+/// 
+#[derive(Clone, Serialize)]
+pub struct Desk {
+    cells: Vec<Cell>,
+    x_range: i8,
+    y_range: i8,
+}
+
+impl Desk {
+    /// empty desk of `x` columns and `y` rows, fails with `InvalidDeskSize` for more than `MAX_BOARD_CELLS` cells
+    pub fn new(x:i8, y:i8) -> Result<Self, GameError> {
+        if x < 1 || y < 1 || x as i16 * y as i16 > MAX_BOARD_CELLS as i16 {
+            return Err(GameError::InvalidDeskSize);
+        }
+        Ok(Desk {
+        cells: Self::generate_desk(x, y),
+        x_range: x,
+        y_range: y
+    })
+    }
+
+    /// desk from cells in any order, e.g. read from battles saved before cells were kept row by row.
+    /// Units get the position of the cell they land in, with its row-major id.
+    pub fn from_cells(x:i8, y:i8, cells: Vec<Cell>) -> Result<Self, GameError> {
+        let mut desk = Self::new(x, y)?;
+        for cell in cells {
+            if let Some(index) = desk.index_of(cell.position.x, cell.position.y) {
+                let position = desk.cells[index].position;
+                desk.cells[index].unit = cell.unit.map(|mut unit| {
+                    unit.current_position = position;
+                    unit
+                });
+            }
+        }
+        Ok(desk)
+    }
+
+    fn generate_desk(x:i8, y:i8) -> Vec<Cell> {
+        let mut cells = vec![];
+        let mut index =0_i8;
+        for j in 0..y {
+            for i in 0..x {
+                cells.push(Cell::new(i, j, index));
+                index+=1;
+            }
+        }
+        cells
+    }
+
+    /// index of the cell at (x, y) in `cells`, `None` when it is off the desk
+    fn index_of(&self, x: i8, y: i8) -> Option<usize> {
+        if x < 0 || y < 0 || x >= self.x_range || y >= self.y_range {
+            return None;
+        }
+        Some(y as usize * self.x_range as usize + x as usize)
+    }
+
+    pub fn width(&self) -> i8 {
+        self.x_range
+    }
+
+    pub fn height(&self) -> i8 {
+        self.y_range
+    }
+
+    /// all cells row by row
+    pub fn cells(&self) -> &[Cell] {
+        &self.cells
+    }
+
+    pub fn cell(&self, x: i8, y: i8) -> Option<&Cell> {
+        self.index_of(x, y).map(|index| &self.cells[index])
+    }
+
+    fn cell_mut(&mut self, x: i8, y: i8) -> Option<&mut Cell> {
+        self.index_of(x, y).map(move |index| &mut self.cells[index])
+    }
+
+    pub fn get_cell_at(&self, x:i8, y:i8) -> Result<Cell, GameError> {
+        self.cell(x, y).copied().ok_or(GameError::OutOfBounds)
+    }
+
+    pub fn insert_unit_at_cell(&mut self, x:i8, y:i8, unit: Option<Unit>) -> Result<(), GameError> {
+        self.cell_mut(x, y).ok_or(GameError::OutOfBounds)?.unit = unit;
+        Ok(())
+    }
+
+    pub fn get_cells_to_deal_damage(&self, position: Vector2, unit_type: UnitType) -> Result<Vec<Option<&Cell>>, GameError> {
+        if self.index_of(position.x, position.y).is_none() {
+            return Err(GameError::OutOfBounds);
+        }
+        Ok(match unit_type {
+            UnitType::Orc => {
+                self.get_orc_damage_cells(position)
+            },
+            UnitType::Mage => self.get_mage_damage_cells(position),
+            UnitType::Skeleton => self.get_skeleton_damage_cells(position),
+        })
+    } 
+
+    /// cells at `offsets` from `position`, `None` for the ones off the desk
+    fn get_cells_at_offsets(&self, position: Vector2, offsets: &[(i8, i8)]) -> Vec<Option<&Cell>> {
+        offsets.iter().map(|(dx, dy)| self.cell(position.x + dx, position.y + dy)).collect()
+    }
+
+    fn get_orc_damage_cells(&self, position: Vector2) -> Vec<Option<&Cell>> {
+        self.get_cells_at_offsets(position, &[(1, 0), (0, 1), (-1, 0), (0, -1), (1, 1), (-1, -1), (-1, 1), (1, -1)])
+    }
+
+    fn get_mage_damage_cells(&self, position: Vector2) -> Vec<Option<&Cell>> {
+        self.get_cells_at_offsets(position, &[(1, 0), (2, 0), (-1, 0), (-2, 0), (0, 1), (0, 2), (0, -1), (0, -2)])
+    }
+
+    fn get_skeleton_damage_cells(&self, position: Vector2) -> Vec<Option<&Cell>> {
+        self.get_cells_at_offsets(position, &[(1, 0), (-1, 0), (0, 1), (0, -1)])
+    }
+
+    pub fn place_unit(&mut self, position: Vector2, unit: Unit) -> Result<(), GameError> {
+        if self.get_cell_at(position.x, position.y)?.unit.is_some() {
+            return Err(GameError::CellOccupied);
+        }
+        self.insert_unit_at_cell(position.x, position.y, Some(unit))
+    }
+
+    fn deal_damage_at_cell(&mut self, x: i8, y:i8, damage: f32) -> Option<DamagedUnit> {
+        let cell = self.cell_mut(x, y)?;
+        let u = cell.unit.as_mut()?;
+        let hp_before = u.stats.hp;
+        u.stats.hp -= damage;
+        let damaged_unit = DamagedUnit { unit_id: u.unit_id, hp_before, hp_after: u.stats.hp.max(0.0) };
+        if u.stats.hp <= 0.0 {
+            cell.unit = None
+        }
+        Some(damaged_unit)
+    }
+}
+
+/// Unit as it is stored in a desk, stats other than hp and the position follow from the rest
+#[derive(BorshDeserialize, BorshSerialize)]
+struct PackedUnit {
+    unit_id: i8,
+    unit_type: UnitType,
+    level: i8,
+    hp: f32,
+}
+
+impl BorshSerialize for Desk {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        BorshSerialize::serialize(&self.x_range, writer)?;
+        BorshSerialize::serialize(&self.y_range, writer)?;
+        let mut occupied: u64 = 0;
+        for (index, cell) in self.cells.iter().enumerate() {
+            if cell.unit.is_some() {
+                occupied |= 1 << index;
+            }
+        }
+        BorshSerialize::serialize(&occupied, writer)?;
+        for unit in self.cells.iter().filter_map(|c| c.unit) {
+            let packed = PackedUnit { unit_id: unit.unit_id, unit_type: unit.unit_type, level: unit.level, hp: unit.stats.hp };
+            BorshSerialize::serialize(&packed, writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl BorshDeserialize for Desk {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        let x_range = <i8 as BorshDeserialize>::deserialize(buf)?;
+        let y_range = <i8 as BorshDeserialize>::deserialize(buf)?;
+        let mut desk = Desk::new(x_range, y_range)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Desk is too big"))?;
+        let occupied = <u64 as BorshDeserialize>::deserialize(buf)?;
+        for cell in desk.cells.iter_mut() {
+            if occupied & (1 << cell.position.id) == 0 {
+                continue;
+            }
+            let packed = <PackedUnit as BorshDeserialize>::deserialize(buf)?;
+            let mut unit = Unit::new(packed.level, packed.unit_type, packed.unit_id);
+            unit.stats.hp = packed.hp;
+            unit.current_position = cell.position;
+            cell.unit = Some(unit);
+        }
+        Ok(desk)
+    }
+}
+
+/// Unit hit by the splash damage at the end of a move
+#[derive(Clone, Copy, Serialize)]
+pub struct DamagedUnit {
+    pub unit_id: i8,
+    pub hp_before: f32,
+    /// 0 when the unit was killed
+    pub hp_after: f32,
+}
+
+/// Everything that happened during one move, returned by `make_move`
+#[derive(Serialize)]
+pub struct MoveOutcome<P> {
+    pub unit_id: i8,
+    pub final_position: (i8, i8),
+    /// base damage of the unit is multiplied by it, 1.5 for one hop, 2 for two and so on
+    pub damage_multiplier: f32,
+    pub damage: f32,
+    pub damaged_units: Vec<DamagedUnit>,
+    pub killed_units: Vec<i8>,
+    /// `None` when the battle ended with this move
+    pub next_move_owner: Option<P>,
+    pub battle_ended: bool,
+    pub winner: Option<P>,
+}
+
+/// Entry of the unit registry of a game, `unit.current_position` is kept in sync with the desk
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct UnitEntry<P> {
+    pub owner: P,
+    pub unit: Unit,
+}
+
+impl From<&Desk> for Desk {
+    fn from(d: &Desk) -> Self {
+        Self {
+            cells: d.cells.clone(),
+            x_range: d.x_range,
+            y_range: d.y_range
+        }
+    }
+}
+
+/// Game between two players identified by `P`.
+/// `current_state` is -1 until the second player joins, 0 while units are placed,
+/// 2 while the game is played and 3 once it is won.
+#[derive(Clone)]
+pub struct Game<P> {
+    pub first_player: Option<P>,
+    pub second_player: Option<P>,
+    pub desk: Desk,
+    pub first_player_units: Vec<i8>,
+    pub second_player_units: Vec<i8>,
+    /// living units by unit id, killed units are removed.
+    /// Not stored, it is rebuilt from the desk when the game is read
+    pub units: BTreeMap<i8, UnitEntry<P>>,
+    pub current_state: i8,
+    pub last_unit_id: i8,
+    pub current_move_owner: i8,
+    pub winner: Option<P>,
+    pub move_count: u16,
+    /// every successful `place_units` and `make_move` call in order
+    pub history: Vec<Record>,
+}
+
+impl<P: BorshSerialize> BorshSerialize for Game<P> {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        BorshSerialize::serialize(&self.first_player, writer)?;
+        BorshSerialize::serialize(&self.second_player, writer)?;
+        BorshSerialize::serialize(&self.desk, writer)?;
+        BorshSerialize::serialize(&self.first_player_units, writer)?;
+        BorshSerialize::serialize(&self.second_player_units, writer)?;
+        BorshSerialize::serialize(&self.current_state, writer)?;
+        BorshSerialize::serialize(&self.last_unit_id, writer)?;
+        BorshSerialize::serialize(&self.current_move_owner, writer)?;
+        BorshSerialize::serialize(&self.winner, writer)?;
+        BorshSerialize::serialize(&self.move_count, writer)?;
+        BorshSerialize::serialize(&self.history, writer)
+    }
+}
+
+impl<P: BorshDeserialize + Clone + PartialEq> BorshDeserialize for Game<P> {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        let mut game = Game {
+            first_player: BorshDeserialize::deserialize(buf)?,
+            second_player: BorshDeserialize::deserialize(buf)?,
+            desk: BorshDeserialize::deserialize(buf)?,
+            first_player_units: BorshDeserialize::deserialize(buf)?,
+            second_player_units: BorshDeserialize::deserialize(buf)?,
+            units: BTreeMap::new(),
+            current_state: BorshDeserialize::deserialize(buf)?,
+            last_unit_id: BorshDeserialize::deserialize(buf)?,
+            current_move_owner: BorshDeserialize::deserialize(buf)?,
+            winner: BorshDeserialize::deserialize(buf)?,
+            move_count: BorshDeserialize::deserialize(buf)?,
+            history: BorshDeserialize::deserialize(buf)?,
+        };
+        game.register_desk_units();
+        Ok(game)
+    }
+}
+
+impl<P: Clone + PartialEq> Game<P> {
+
+    /// game created by `creator` on a desk of `x` columns and `y` rows, see `Desk::new`
+    pub fn new(creator: P, x: i8, y: i8) -> Result<Self, GameError> {
+        Ok(Game {
+            first_player: Some(creator),
+            second_player: None,
+            desk: Desk::new(x, y)?,
+            first_player_units: vec![],
+            second_player_units: vec![],
+            units: BTreeMap::new(),
+            current_state: -1,
+            last_unit_id: 0,
+            current_move_owner: 0,
+            winner: None,
+            move_count: 0,
+            history: vec![]
+        })
+    }
+
+    /// rebuilds the unit registry from the units standing on the desk
+    pub fn register_desk_units(&mut self) {
+        self.units.clear();
+        for cell in self.desk.cells.iter_mut() {
+            if let Some(unit) = cell.unit.as_mut() {
+                unit.current_position = cell.position;
+                let owner = if self.first_player_units.contains(&unit.unit_id) { &self.first_player } else { &self.second_player };
+                if let Some(owner) = owner.clone() {
+                    self.units.insert(unit.unit_id, UnitEntry { owner, unit: *unit });
+                }
+            }
+        }
+    }
+
+    /// player whose turn it is, `None` unless the game is being played
+    pub fn move_owner(&self) -> Option<P> {
+        match (self.current_state, self.current_move_owner) {
+            (2, 0) => self.first_player.clone(),
+            (2, _) => self.second_player.clone(),
+            _ => None
+        }
+    }
+
+    pub fn owner_of(&self, unit_id: i8) -> Option<P> {
+        if self.first_player_units.contains(&unit_id) {
+            self.first_player.clone()
+        }
+        else if self.second_player_units.contains(&unit_id) {
+            self.second_player.clone()
+        }
+        else {
+            None
+        }
+    }
+
+    /// hop chains the unit can make: a step to a free neighbour cell or a chain of jumps
+    /// over units to free cells, every chain is accepted by `make_move`
+    pub fn legal_moves(&self, unit_id: i8) -> Vec<Vec<(i8,i8)>> {
+        let Some(entry) = self.units.get(&unit_id) else {
+            return vec![];
+        };
+        let start = (entry.unit.current_position.x, entry.unit.current_position.y);
+        let mut moves = vec![];
+        for (dx, dy) in HOP_DIRECTIONS {
+            if self.is_free(start.0 + dx, start.1 + dy) {
+                moves.push(vec![start, (start.0 + dx, start.1 + dy)]);
+            }
+        }
+        self.collect_jumps(&mut vec![start], &mut moves);
+        moves
+    }
+
+    /// legal moves of every living unit of the player as `(unit_id, moves)`
+    pub fn legal_moves_of(&self, player: &P) -> Vec<(i8, Vec<(i8,i8)>)> {
+        self.units.iter()
+            .filter(|(_, entry)| &entry.owner == player)
+            .flat_map(|(unit_id, _)| self.legal_moves(*unit_id).into_iter().map(move |moves| (*unit_id, moves)))
+            .collect()
+    }
+
+    fn collect_jumps(&self, chain: &mut Vec<(i8,i8)>, moves: &mut Vec<Vec<(i8,i8)>>) {
+        let (x, y) = chain[chain.len() - 1];
+        for (dx, dy) in HOP_DIRECTIONS {
+            let landing = (x + 2 * dx, y + 2 * dy);
+            let jumped = self.desk.cell(x + dx, y + dy).is_some_and(|c| c.unit.is_some());
+            if jumped && self.is_free(landing.0, landing.1) && !chain.contains(&landing) {
+                chain.push(landing);
+                moves.push(chain.clone());
+                self.collect_jumps(chain, moves);
+                chain.pop();
+            }
+        }
+    }
+
+    fn is_free(&self, x: i8, y: i8) -> bool {
+        self.desk.cell(x, y).is_some_and(|c| c.unit.is_none())
+    }
+
+    pub fn join(&mut self, player_id: P) -> Result<(), GameError> {
+        if self.second_player.is_some() {
+            return Err(GameError::BattleFull);
+        }
+        if self.current_state != -1 {
+            return Err(GameError::WrongState);
+        }
+        self.second_player = Some(player_id);
+        self.current_state = 0;
+        Ok(())
+    }
+
+    pub fn make_move(&mut self, unit_id: i8, moves: Vec<(i8,i8)>, caller: P) -> Result<MoveOutcome<P>, GameError> {
+        if self.current_state != 2 {
+            return Err(GameError::WrongState);
+        }
+        if self.first_player.as_ref() == Some(&caller) {
+            if !self.first_player_units.contains(&unit_id) {
+                return Err(GameError::NotYourUnit);
+            }
+            if self.current_move_owner != 0 {
+                return Err(GameError::NotYourTurn);
+            }
+        }
+        else if self.second_player.as_ref() == Some(&caller) {
+            if !self.second_player_units.contains(&unit_id) {
+                return Err(GameError::NotYourUnit);
+            }
+            if self.current_move_owner != 1 {
+                return Err(GameError::NotYourTurn);
+            }
+        }
+        else {
+            return Err(GameError::NotYourBattle);
+        }
+        let length = moves.len();
+        if length < 2 {
+            return Err(GameError::IllegalHop);
+        }
+        let (start_x, start_y) = moves[0];
+        let mut unit = self.units.get(&unit_id).map(|entry| entry.unit).ok_or(GameError::UnitNotFound)?;
+        let initial_damage = unit.stats.damage;
+        if self.desk.get_cell_at(start_x, start_y)?.unit.map(|u| u.unit_id) != Some(unit_id) {
+            return Err(GameError::NotYourUnit);
+        }
+        let mut damage = initial_damage;
+        for i in 0..length-1 {
+            let (x1,y1) = moves[i];
+            let (x2,y2) = moves[i+1];
+            let pos_1 = Vector2 { x: x1, y: y1, id: 100 };
+            let pos_2 = Vector2 { x: x2, y: y2, id: 100 };
+            if self.desk.get_cell_at(x2, y2)?.unit.is_some() && (x2, y2) != (start_x, start_y) {
+                return Err(GameError::CellOccupied);
+            }
+            if self.is_valid_move(pos_1, pos_2) {
+                if self.is_unit_between(pos_1, pos_2){
+                    damage = initial_damage * (1_f32+0.5*(i as i8+1) as f32);
+                }
+            }
+            else {
+                return Err(GameError::IllegalHop);
+            }
+        }
+        let (end_x, end_y) = moves[length - 1];
+        unit.current_position = self.desk.get_cell_at(end_x, end_y)?.position;
+        self.desk.insert_unit_at_cell(start_x, start_y, None)?;
+        self.desk.insert_unit_at_cell(end_x, end_y, Some(unit))?;
+        if let Some(entry) = self.units.get_mut(&unit_id) {
+            entry.unit = unit;
+        }
+        let damaged_units = self.deal_damage(damage, Vector2 { x: end_x, y: end_y, id: -10 }, unit.unit_type)?;
+        self.move_count += 1;
+        if self.current_move_owner == 0 {
+            self.current_move_owner = 1;
+        }
+        else if self.current_move_owner == 1 {
+            self.current_move_owner = 0;
+        }
+        if self.first_player_units.is_empty() {
+            self.winner = Some(self.second_player.clone().unwrap());
+            self.current_state = 3;
+        }
+        else if self.second_player_units.is_empty() {
+            self.winner = Some(self.first_player.clone().unwrap());
+            self.current_state = 3;
+        }
+        let battle_ended = self.winner.is_some();
+        self.history.push(Record::Move {
+            first_player: self.first_player.as_ref() == Some(&caller),
+            unit_type: Unit::get_id_by_unit_type(unit.unit_type),
+            moves
+        });
+        Ok(MoveOutcome {
+            unit_id,
+            final_position: (end_x, end_y),
+            damage_multiplier: damage / initial_damage,
+            damage,
+            killed_units: damaged_units.iter().filter(|u| u.hp_after <= 0.0).map(|u| u.unit_id).collect(),
+            damaged_units,
+            next_move_owner: self.move_owner(),
+            battle_ended,
+            winner: self.winner.clone()
+        })
+    }
+
+    /// returns every unit that was hit, including the killed ones
+    fn deal_damage(&mut self, damage: f32, cell_position: Vector2, unit_type: UnitType) -> Result<Vec<DamagedUnit>, GameError> {
+        let binding = self.desk.clone();
+        let cells = binding.get_cells_to_deal_damage(cell_position, unit_type)?;
+        let mut damaged_units = vec![];
+        cells.iter().for_each(|x| {
+            if x.is_some() && x.unwrap().unit.is_some() {
+                let damaged_unit = self.desk.deal_damage_at_cell(x.unwrap().position.x, x.unwrap().position.y, damage).unwrap();
+                damaged_units.push(damaged_unit);
+                let unit_to_delete = damaged_unit.unit_id;
+                if let Some(entry) = self.units.get_mut(&unit_to_delete) {
+                    entry.unit.stats.hp = damaged_unit.hp_after;
+                }
+                if damaged_unit.hp_after <= 0.0 {
+                    self.units.remove(&unit_to_delete);
+                    if self.first_player_units.contains(&unit_to_delete) {
+                        let index = self.first_player_units.iter().position(|&x| x == unit_to_delete);
+                        if let Some(index) = index {
+                            self.first_player_units.remove(index);
+                        }
+                    }
+                    else if self.second_player_units.contains(&unit_to_delete) {
+                        let index = self.second_player_units.iter().position(|&x| x == unit_to_delete);
+                        if let Some(index) = index {
+                            self.second_player_units.remove(index);
+                        }
+                    }
+
+                }
+            }
+        });
+        //initial_desk_cells.iter().filter(|x| x.position.x == cells.iter().any(f))
+        Ok(damaged_units)
+    } 
+
+    fn is_valid_move(&self, pos_1: Vector2, pos_2: Vector2) -> bool {
+        match self.get_move_vector(pos_1, pos_2) {
+            0 => {
+                if self.is_valid_y_move(pos_1.y, pos_2.y, pos_1.x) {
+                    return true
+                }
+                
+            },
+            1 => {
+                if self.is_valid_x_move(pos_1.x, pos_2.x, pos_1.y) {
+                    return true
+                }
+            },
+            _ => return false
+        }
+        false
+    }
+
+    fn is_unit_between(&self, pos_1: Vector2, pos_2: Vector2) -> bool {
+        let min_x = std::cmp::min(pos_1.x,pos_2.x);
+        let min_y = std::cmp::min(pos_1.y, pos_2.y);
+        match self.get_move_vector(pos_1, pos_2) {
+            0 => {
+                if self.desk.get_cell_at(pos_1.x, min_y+1).is_ok_and(|c| c.unit.is_some()) {
+                    return true
+                }
+                false
+            },
+            1 => {
+                
+                if self.desk.get_cell_at(min_x + 1, pos_1.y).is_ok_and(|c| c.unit.is_some()) {
+                    return true
+                }
+                false
+            },
+            _ => false
+        }
+    }
+
+    fn get_move_vector(&self, pos_1: Vector2, pos_2: Vector2) -> i8 {
+        if pos_1.x == pos_2.x {
+            return 0
+        }
+        else if pos_1.y == pos_2.y {
+            return 1
+        }
+        -1
+    }
+
+    fn is_valid_x_move(&self, x_1: i8, x_2: i8, y: i8) -> bool {
+        let max = std::cmp::max(x_1, x_2);
+        let min = std::cmp::min(x_1, x_2);
+        if max - min <= 0 {
+            return false
+        }
+        else if max - min == 2 && self.desk.get_cell_at(min+1, y).is_ok_and(|c| c.unit.is_some()) {
+            return true
+        }
+        if max - min == 1 {
+            return true;
+        }
+        false
+    }
+
+    fn is_valid_y_move(&self, y_1: i8, y_2:i8, x: i8) -> bool {
+        let max = std::cmp::max(y_1,y_2);
+        let min = std::cmp::min(y_1, y_2);
+        if max - min <= 0 {
+            return false
+        }
+        else if max-min == 2 && self.desk.get_cell_at(x, min+1).is_ok_and(|c| c.unit.is_some()) {
+            return true
+        }
+        if max - min == 1 {
+            return true;
+        }
+        false
+    }
+
+    pub fn place_units(&mut self, units: Vec<(i8,i8,i8)>, caller: P) -> Result<(), GameError> {
+        if self.current_state != 0 {
+            return Err(GameError::WrongState);
+        }
+        if units.len() > 6 {
+            return Err(GameError::TooManyUnits);
+        }
+        if self.first_player.as_ref() == Some(&caller) {
+            if self.first_player_units.len() + units.len() > 6 {
+                return Err(GameError::TooManyUnits);
+            }
+        }
+        else if self.second_player.as_ref() == Some(&caller) {
+            if self.second_player_units.len() + units.len() > 6 {
+                return Err(GameError::TooManyUnits);
+            }
+        }
+        else {
+            return Err(GameError::NotYourBattle);
+        }
+        let first_player = self.first_player.as_ref() == Some(&caller);
+        // the whole batch is checked before anything is placed, so a bad unit leaves no trace
+        let mut positions = Vec::with_capacity(units.len());
+        for &(unit_type, x, y) in &units {
+            Unit::get_unit_type_by_id(unit_type)?;
+            let allowed = if first_player {
+                x >= 0 && x < self.desk.x_range / 2
+            } else {
+                x < self.desk.x_range && x >= self.desk.x_range - self.desk.x_range / 2
+            };
+            if !allowed {
+                return Err(GameError::PlacementNotAllowed);
+            }
+            if self.desk.get_cell_at(x, y)?.unit.is_some() || positions.contains(&(x, y)) {
+                return Err(GameError::CellOccupied);
+            }
+            positions.push((x, y));
+        }
+        let record = Record::Placement { first_player, units: units.clone() };
+        for (unit_type, x, y) in units {
+            let mut new_unit = Unit::new(1, Unit::get_unit_type_by_id(unit_type)?, self.last_unit_id);
+            if first_player {
+                self.first_player_units.push(self.last_unit_id);
+            }
+            else {
+                self.second_player_units.push(self.last_unit_id);
+            }
+            new_unit.current_position = self.desk.get_cell_at(x, y)?.position;
+            self.desk.place_unit(Vector2::new(x, y, 0), new_unit)?;
+            self.units.insert(self.last_unit_id, UnitEntry { owner: caller.clone(), unit: new_unit });
+            self.last_unit_id += 1;
+        }
+        if !self.first_player_units.is_empty() && !self.second_player_units.is_empty() {
+            self.current_state = 2;
+        }
+        self.history.push(record);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn desks_must_fit_the_occupied_mask() {
+        assert_eq!(Desk::new(8, 8).unwrap().cells().len(), 64);
+        for (x, y) in [(13, 5), (20, 20), (127, 127), (0, 4), (7, -1)] {
+            assert_eq!(Desk::new(x, y).err(), Some(GameError::InvalidDeskSize));
+            assert_eq!(Game::new(1u8, x, y).err(), Some(GameError::InvalidDeskSize));
+        }
+        assert!(Desk::from_cells(9, 8, vec![]).is_err());
+    }
+
+    #[test]
+    fn desk_cells_keep_row_major_order() {
+        let mut desk = Desk::new(7, 4).unwrap();
+        let positions: Vec<(i8, i8)> = desk.cells.iter().map(|c| (c.position.x, c.position.y)).collect();
+        let cell = desk.cells[2 * 7 + 3].position;
+        assert_eq!((cell.x, cell.y, cell.id), (3, 2, 17));
+        desk.place_unit(Vector2::new(3, 2, 0), Unit::new(1, UnitType::Orc, 0)).unwrap();
+        desk.insert_unit_at_cell(3, 2, None).unwrap();
+        desk.insert_unit_at_cell(6, 3, Some(Unit::new(1, UnitType::Mage, 1))).unwrap();
+        assert_eq!(desk.cells.iter().map(|c| (c.position.x, c.position.y)).collect::<Vec<_>>(), positions);
+        assert_eq!(desk.cell(6, 3).and_then(|c| c.unit).map(|u| u.unit_id), Some(1));
+        assert!(desk.cell(7, 0).is_none() && desk.cell(0, 4).is_none() && desk.cell(-1, 0).is_none());
+        assert_eq!(desk.insert_unit_at_cell(0, -1, None).err(), Some(GameError::OutOfBounds));
+    }
+
+    #[test]
+    fn games_can_be_played_with_any_player_ids() {
+        let mut game: Game<u8> = Game::new(1, 7, 4).unwrap();
+        game.join(2).unwrap();
+        game.place_units(vec![(2, 1, 0), (1, 2, 0)], 1).unwrap();
+        game.place_units(vec![(0, 4, 0)], 2).unwrap();
+        assert_eq!(game.move_owner(), Some(1));
+        assert_eq!(game.make_move(0, vec![(1, 0), (2, 0)], 1).err(), Some(GameError::CellOccupied));
+
+        let outcome = game.make_move(0, vec![(1, 0), (3, 0)], 1).unwrap();
+        assert_eq!(outcome.killed_units, vec![2]);
+        assert_eq!(outcome.winner, Some(1));
+        assert_eq!(game.current_state, 3);
+        assert_eq!(game.move_owner(), None);
+    }
+
+    #[test]
+    fn rejected_placement_leaves_no_units_behind() {
+        let mut game: Game<u8> = Game::new(1, 7, 4).unwrap();
+        game.join(2).unwrap();
+        assert_eq!(game.place_units(vec![(2, 1, 0), (1, 1, 0)], 1).err(), Some(GameError::CellOccupied));
+        assert_eq!(game.place_units(vec![(2, 1, 0), (1, 5, 0)], 1).err(), Some(GameError::PlacementNotAllowed));
+        assert_eq!(game.place_units(vec![(2, 1, 0), (1, 2, 9)], 1).err(), Some(GameError::OutOfBounds));
+        assert!(game.first_player_units.is_empty() && game.units.is_empty() && game.history.is_empty());
+        assert!(game.desk.cell(1, 0).is_some_and(|c| c.unit.is_none()));
+
+        game.place_units(vec![(2, 1, 0)], 1).unwrap();
+        assert_eq!(game.first_player_units, vec![0]);
+        assert_eq!(game.place_units(vec![(0, 4, 0), (0, 1, 0)], 2).err(), Some(GameError::PlacementNotAllowed));
+        assert!(game.second_player_units.is_empty() && game.units.len() == 1 && game.current_state == 0);
+    }
+
+    #[test]
+    fn stored_game_rebuilds_its_unit_registry() {
+        let mut game: Game<u8> = Game::new(1, 7, 4).unwrap();
+        game.join(2).unwrap();
+        game.place_units(vec![(2, 1, 0), (1, 2, 0)], 1).unwrap();
+        game.place_units(vec![(0, 4, 0)], 2).unwrap();
+
+        let bytes = game.try_to_vec().unwrap();
+        let mut stored = game.clone();
+        stored.units.clear();
+        assert_eq!(bytes, stored.try_to_vec().unwrap());
+        let read = Game::<u8>::try_from_slice(&bytes).unwrap();
+        let entries = |g: &Game<u8>| g.units.iter()
+            .map(|(id, e)| (*id, e.owner, e.unit.current_position.x, e.unit.current_position.y))
+            .collect::<Vec<_>>();
+        assert_eq!(entries(&read), entries(&game));
+        assert_eq!(entries(&read).len(), 3);
+    }
+
+    #[test]
+    fn legal_moves_are_accepted_by_make_move() {
+        let mut game: Game<u8> = Game::new(1, 7, 4).unwrap();
+        game.join(2).unwrap();
+        game.place_units(vec![(2, 1, 0), (1, 2, 0), (0, 2, 1), (1, 0, 3)], 1).unwrap();
+        game.place_units(vec![(1, 4, 0), (2, 4, 1), (0, 6, 3)], 2).unwrap();
+
+        let skeleton_moves = game.legal_moves(0);
+        assert!(skeleton_moves.contains(&vec![(1, 0), (0, 0)]) && skeleton_moves.contains(&vec![(1, 0), (1, 1)]));
+        assert!(skeleton_moves.contains(&vec![(1, 0), (3, 0), (5, 0)]));
+        assert!(!skeleton_moves.contains(&vec![(1, 0), (2, 0)]));
+        let moves = game.legal_moves_of(&1);
+        assert_eq!(moves.len(), [0, 1, 2, 3].iter().map(|id| game.legal_moves(*id).len()).sum::<usize>());
+        for (unit_id, chain) in moves {
+            assert!(game.clone().make_move(unit_id, chain.clone(), 1).is_ok(), "{} {:?}", unit_id, chain);
+        }
+        assert!(game.legal_moves(10).is_empty());
+    }
+}
@@ -0,0 +1,332 @@
+//! Text notation of battles.
+//!
+//! Squares are a file letter for x (`a` is 0, after `z` come `aa`, `ab`...) and a rank for y counted from 1,
+//! so `b3` is (1, 2). Units are `O`rc, `M`age and `S`keleton, upper case for the first player and lower case
+//! for the second one. A setup line lists units placed with one `place_units` call (`O@a2 S@b1`),
+//! a move is the unit and its hop chain (`M b3-b5-d5`), optionally numbered (`4. M b3-b5-d5`).
+//! Tags in brackets (`[Desk "7x4"]`) come first, `;` starts a comment.
+//!
+//! ```text
+//! [Desk "7x4"]
+//! [First "alice"]
+//! [Second "bob"]
+//! [Result "1-0"]
+//!
+//! S@b1 O@c1
+//! m@e1
+//! 1. S b1-d1
+//! ```
+
+use crate::{Game, GameError, Unit, UnitType, MAX_BOARD_CELLS};
+use borsh::{BorshDeserialize, BorshSerialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// One `place_units` or `make_move` call of a battle, kept in `Game::history`
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq, Eq)]
+pub enum Record {
+    /// units as `(unit_type, x, y)`, like `place_units` takes them
+    Placement { first_player: bool, units: Vec<(i8, i8, i8)> },
+    /// hop chain of the moved unit, its first position is where the unit stood
+    Move { first_player: bool, unit_type: i8, moves: Vec<(i8, i8)> },
+}
+
+/// Tags and records of a battle written in the text notation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Notation {
+    /// tag pairs like `("Desk", "7x4")` in the order they are written
+    pub tags: Vec<(String, String)>,
+    pub records: Vec<Record>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NotationError {
+    InvalidSquare(String),
+    InvalidUnit(String),
+    InvalidTag(String),
+    /// line that is neither a tag, a setup nor a move
+    InvalidLine(String),
+    /// record with this index was rejected by the rules
+    Rejected(usize, GameError),
+}
+
+impl fmt::Display for NotationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotationError::InvalidSquare(s) => write!(f, "`{}` is not a square", s),
+            NotationError::InvalidUnit(s) => write!(f, "`{}` is not a unit", s),
+            NotationError::InvalidTag(s) => write!(f, "`{}` is not a valid tag", s),
+            NotationError::InvalidLine(s) => write!(f, "`{}` is neither a setup nor a move", s),
+            NotationError::Rejected(i, e) => write!(f, "record {} breaks the rules: {:?}", i + 1, e),
+        }
+    }
+}
+
+pub fn format_square(x: i8, y: i8) -> String {
+    let mut file = String::new();
+    let mut n = x as i32;
+    loop {
+        file.insert(0, (b'a' + (n % 26) as u8) as char);
+        n /= 26;
+        if n == 0 {
+            break;
+        }
+        n -= 1;
+    }
+    format!("{}{}", file, y as i32 + 1)
+}
+
+pub fn parse_square(s: &str) -> Result<(i8, i8), NotationError> {
+    let invalid = || NotationError::InvalidSquare(s.to_string());
+    let split = s.find(|c: char| !c.is_ascii_lowercase()).ok_or_else(invalid)?;
+    let (file, rank) = s.split_at(split);
+    if file.is_empty() || !rank.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let x = file.bytes().try_fold(0_i32, |x, b| x.checked_mul(26)?.checked_add((b - b'a') as i32 + 1)).ok_or_else(invalid)? - 1;
+    let y = rank.parse::<i32>().map_err(|_| invalid())? - 1;
+    Ok((i8::try_from(x).map_err(|_| invalid())?, i8::try_from(y).map_err(|_| invalid())?))
+}
+
+fn unit_letter(unit_type: i8, first_player: bool) -> char {
+    let letter = match Unit::get_unit_type_by_id(unit_type) {
+        Ok(UnitType::Orc) => 'o',
+        Ok(UnitType::Mage) => 'm',
+        Ok(UnitType::Skeleton) => 's',
+        Err(_) => '?',
+    };
+    if first_player { letter.to_ascii_uppercase() } else { letter }
+}
+
+/// returns unit type and whether the unit belongs to the first player
+fn parse_unit(s: &str) -> Result<(i8, bool), NotationError> {
+    let unit_type = match s {
+        "M" | "m" => 0,
+        "O" | "o" => 1,
+        "S" | "s" => 2,
+        _ => return Err(NotationError::InvalidUnit(s.to_string())),
+    };
+    Ok((unit_type, s.chars().all(|c| c.is_ascii_uppercase())))
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Record::Placement { first_player, units } => {
+                let units: Vec<String> = units.iter()
+                    .map(|&(unit_type, x, y)| format!("{}@{}", unit_letter(unit_type, *first_player), format_square(x, y)))
+                    .collect();
+                write!(f, "{}", units.join(" "))
+            }
+            Record::Move { first_player, unit_type, moves } => {
+                let squares: Vec<String> = moves.iter().map(|&(x, y)| format_square(x, y)).collect();
+                write!(f, "{} {}", unit_letter(*unit_type, *first_player), squares.join("-"))
+            }
+        }
+    }
+}
+
+impl FromStr for Record {
+    type Err = NotationError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let invalid = || NotationError::InvalidLine(line.to_string());
+        let mut tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.first().is_some_and(|t| t.ends_with('.') && t[..t.len() - 1].bytes().all(|b| b.is_ascii_digit())) {
+            tokens.remove(0);
+        }
+        if tokens.first().is_some_and(|t| t.contains('@')) {
+            let mut first_player = None;
+            let mut units = vec![];
+            for token in tokens {
+                let (unit, square) = token.split_once('@').ok_or_else(invalid)?;
+                let (unit_type, first) = parse_unit(unit)?;
+                if *first_player.get_or_insert(first) != first {
+                    return Err(invalid());
+                }
+                let (x, y) = parse_square(square)?;
+                units.push((unit_type, x, y));
+            }
+            return Ok(Record::Placement { first_player: first_player.ok_or_else(invalid)?, units });
+        }
+        let [unit, chain] = tokens.as_slice() else {
+            return Err(invalid());
+        };
+        let (unit_type, first_player) = parse_unit(unit)?;
+        let moves = chain.split('-').map(parse_square).collect::<Result<Vec<_>, _>>()?;
+        Ok(Record::Move { first_player, unit_type, moves })
+    }
+}
+
+impl Notation {
+    /// tags with the desk size, players and result followed by the history of the game
+    pub fn of_game<P: fmt::Display + Clone + PartialEq>(game: &Game<P>) -> Self {
+        let mut tags = vec![("Desk".to_string(), format!("{}x{}", game.desk.width(), game.desk.height()))];
+        if let Some(first) = &game.first_player {
+            tags.push(("First".to_string(), first.to_string()));
+        }
+        if let Some(second) = &game.second_player {
+            tags.push(("Second".to_string(), second.to_string()));
+        }
+        let result = match &game.winner {
+            Some(winner) if game.first_player.as_ref() == Some(winner) => "1-0",
+            Some(_) => "0-1",
+            // finished without a winner
+            None if game.current_state == 3 => "1/2-1/2",
+            None => "*",
+        };
+        tags.push(("Result".to_string(), result.to_string()));
+        Notation { tags, records: game.history.clone() }
+    }
+
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags.iter().find(|(n, _)| n == name).map(|(_, value)| value.as_str())
+    }
+
+    pub fn parse(text: &str) -> Result<Self, NotationError> {
+        let mut notation = Notation { tags: vec![], records: vec![] };
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(tag) = line.strip_prefix('[') {
+                let invalid = || NotationError::InvalidTag(line.to_string());
+                let (name, value) = tag.strip_suffix(']').and_then(|t| t.split_once(' ')).ok_or_else(invalid)?;
+                let value = value.trim().strip_prefix('"').and_then(|v| v.strip_suffix('"')).ok_or_else(invalid)?;
+                notation.tags.push((name.to_string(), value.to_string()));
+            }
+            else {
+                notation.records.push(line.parse()?);
+            }
+        }
+        Ok(notation)
+    }
+
+    /// desk size from the `Desk` tag, 7x4 when there is none. Desks over `MAX_BOARD_CELLS` cells are invalid.
+    pub fn desk_size(&self) -> Result<(i8, i8), NotationError> {
+        let Some(desk) = self.tag("Desk") else {
+            return Ok((7, 4));
+        };
+        desk.split_once('x')
+            .and_then(|(x, y)| Some((x.parse::<i8>().ok()?, y.parse::<i8>().ok()?)))
+            .filter(|&(x, y)| x > 0 && y > 0 && x as i16 * y as i16 <= MAX_BOARD_CELLS as i16)
+            .ok_or_else(|| NotationError::InvalidTag(format!("[Desk \"{}\"]", desk)))
+    }
+
+    /// plays the records by the rules between the `First` and `Second` players
+    pub fn play(&self) -> Result<Game<String>, NotationError> {
+        let (x, y) = self.desk_size()?;
+        let first = self.tag("First").unwrap_or("first").to_string();
+        let second = self.tag("Second").unwrap_or("second").to_string();
+        let mut game = Game::new(first.clone(), x, y).map_err(|e| NotationError::Rejected(0, e))?;
+        game.join(second.clone()).map_err(|e| NotationError::Rejected(0, e))?;
+        for (i, record) in self.records.iter().enumerate() {
+            let rejected = |e| NotationError::Rejected(i, e);
+            match record {
+                Record::Placement { first_player, units } => {
+                    let player = if *first_player { first.clone() } else { second.clone() };
+                    game.place_units(units.clone(), player).map_err(rejected)?;
+                }
+                Record::Move { first_player, unit_type, moves } => {
+                    let player = if *first_player { first.clone() } else { second.clone() };
+                    let &(start_x, start_y) = moves.first().ok_or(rejected(GameError::IllegalHop))?;
+                    let unit = game.desk.cell(start_x, start_y).and_then(|c| c.unit).ok_or(rejected(GameError::UnitNotFound))?;
+                    if Unit::get_id_by_unit_type(unit.unit_type) != *unit_type {
+                        return Err(rejected(GameError::NotYourUnit));
+                    }
+                    game.make_move(unit.unit_id, moves.clone(), player).map_err(rejected)?;
+                }
+            }
+        }
+        Ok(game)
+    }
+}
+
+impl fmt::Display for Notation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in &self.tags {
+            writeln!(f, "[{} \"{}\"]", name, value)?;
+        }
+        if !self.tags.is_empty() {
+            writeln!(f)?;
+        }
+        let mut move_number = 0;
+        for record in &self.records {
+            if let Record::Move { .. } = record {
+                move_number += 1;
+                write!(f, "{}. ", move_number)?;
+            }
+            writeln!(f, "{}", record)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn squares_and_records_round_trip() {
+        assert_eq!(format_square(0, 1), "a2");
+        assert_eq!(format_square(27, 0), "ab1");
+        for (x, y) in [(0, 0), (6, 3), (25, 1), (26, 0), (31, 1)] {
+            assert_eq!(parse_square(&format_square(x, y)), Ok((x, y)));
+        }
+        for square in ["", "a", "3", "A2", "a-1", "b2c"] {
+            assert_eq!(parse_square(square), Err(NotationError::InvalidSquare(square.to_string())));
+        }
+
+        let setup: Record = "O@a2 S@b1".parse().unwrap();
+        assert_eq!(setup, Record::Placement { first_player: true, units: vec![(1, 0, 1), (2, 1, 0)] });
+        let chain: Record = "4. m b3-b5-d5".parse().unwrap();
+        assert_eq!(chain, Record::Move { first_player: false, unit_type: 0, moves: vec![(1, 2), (1, 4), (3, 4)] });
+        assert_eq!(chain.to_string(), "m b3-b5-d5");
+        assert_eq!(setup.to_string().parse(), Ok(setup));
+        assert!("O@a2 s@g1".parse::<Record>().is_err());
+        assert!("X b3-b5".parse::<Record>().is_err());
+    }
+
+    #[test]
+    fn played_game_is_exported_and_imported() {
+        let mut game: Game<u8> = Game::new(1, 7, 4).unwrap();
+        game.join(2).unwrap();
+        game.place_units(vec![(2, 1, 0), (1, 2, 0)], 1).unwrap();
+        game.place_units(vec![(0, 4, 0)], 2).unwrap();
+        game.make_move(0, vec![(1, 0), (3, 0)], 1).unwrap();
+
+        let text = Notation::of_game(&game).to_string();
+        assert_eq!(text, "[Desk \"7x4\"]\n[First \"1\"]\n[Second \"2\"]\n[Result \"1-0\"]\n\nS@b1 O@c1\nm@e1\n1. S b1-d1\n");
+        let notation = Notation::parse(&format!("; shared game\n{}", text)).unwrap();
+        assert_eq!(notation.to_string(), text);
+        let replayed = notation.play().unwrap();
+        assert_eq!(replayed.winner.as_deref(), Some("1"));
+        assert_eq!(replayed.history, game.history);
+
+        let illegal = Notation::parse("S@b1\nm@e1\n1. S b1-b3").unwrap();
+        assert_eq!(illegal.play().err(), Some(NotationError::Rejected(2, GameError::IllegalHop)));
+    }
+
+    #[test]
+    fn draws_and_desk_sizes_are_checked() {
+        let mut game: Game<u8> = Game::new(1, 7, 4).unwrap();
+        game.join(2).unwrap();
+        assert_eq!(Notation::of_game(&game).tag("Result"), Some("*"));
+        // e.g. resolved as a draw by a moderator
+        game.current_state = 3;
+        let text = Notation::of_game(&game).to_string();
+        assert_eq!(text, "[Desk \"7x4\"]\n[First \"1\"]\n[Second \"2\"]\n[Result \"1/2-1/2\"]\n\n");
+        let notation = Notation::parse(&text).unwrap();
+        assert_eq!(notation.tag("Result"), Some("1/2-1/2"));
+        assert_eq!(notation.to_string(), text);
+
+        for desk in ["20x20", "9x8", "0x4", "7x-4", "300x1", "7"] {
+            let notation = Notation::parse(&format!("[Desk \"{}\"]\nS@b1", desk)).unwrap();
+            let invalid = NotationError::InvalidTag(format!("[Desk \"{}\"]", desk));
+            assert_eq!(notation.desk_size(), Err(invalid.clone()));
+            assert_eq!(notation.play().err(), Some(invalid));
+        }
+        assert_eq!(Notation::parse("[Desk \"8x8\"]").unwrap().desk_size(), Ok((8, 8)));
+    }
+}
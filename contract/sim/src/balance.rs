@@ -0,0 +1,306 @@
+//! Monte Carlo balance runs: armies of every composition play each other many times
+//! by the engine rules and the win rates, first-mover advantage and game lengths are reported.
+
+use hopchain_engine::{Game, MAX_BOARD_CELLS};
+
+/// games where nobody won after this many moves are counted as draws
+const DEFAULT_MAX_MOVES: u16 = 200;
+
+/// xorshift64*, good enough to shuffle placements and pick moves with a reproducible seed
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Policy {
+    /// any legal move
+    Random,
+    /// the move with the best `Game::score_move`, random among equal ones
+    Greedy,
+}
+
+/// Settings of a balance run, armies are unit type ids as `place_units` takes them
+pub struct BalanceConfig {
+    pub width: i8,
+    pub height: i8,
+    pub armies: Vec<Vec<i8>>,
+    pub games: u32,
+    /// policy of the first and the second player
+    pub policies: (Policy, Policy),
+    pub max_moves: u16,
+    pub seed: u64,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub struct MatchupStats {
+    pub first_wins: u32,
+    pub second_wins: u32,
+    /// games stopped by the move limit or a player without legal moves
+    pub draws: u32,
+    pub total_moves: u64,
+}
+
+impl MatchupStats {
+    pub fn games(&self) -> u32 {
+        self.first_wins + self.second_wins + self.draws
+    }
+
+    pub fn average_moves(&self) -> f64 {
+        self.total_moves as f64 / self.games().max(1) as f64
+    }
+}
+
+/// Results of every ordered pair of armies, `first` is the army of the first player
+pub struct BalanceReport {
+    pub matchups: Vec<(usize, usize, MatchupStats)>,
+}
+
+enum GameResult {
+    FirstWins,
+    SecondWins,
+    Draw,
+}
+
+/// parses an army like `OOS` or `mms` into unit type ids
+pub fn parse_army(s: &str) -> Result<Vec<i8>, String> {
+    let army = s.chars().map(|c| match c.to_ascii_uppercase() {
+        'M' => Ok(0),
+        'O' => Ok(1),
+        'S' => Ok(2),
+        _ => Err(format!("`{}` in army `{}` is not O, M or S", c, s)),
+    }).collect::<Result<Vec<i8>, String>>()?;
+    if army.is_empty() || army.len() > 6 {
+        return Err(format!("army `{}` must have from 1 to 6 units", s));
+    }
+    Ok(army)
+}
+
+pub fn army_name(army: &[i8]) -> String {
+    army.iter().map(|unit_type| match unit_type {
+        0 => 'M',
+        1 => 'O',
+        _ => 'S',
+    }).collect()
+}
+
+pub fn parse_policy(s: &str) -> Result<Policy, String> {
+    match s {
+        "random" => Ok(Policy::Random),
+        "greedy" => Ok(Policy::Greedy),
+        _ => Err(format!("unknown policy `{}`, use random or greedy", s)),
+    }
+}
+
+impl BalanceConfig {
+    /// reads `--games`, `--armies`, `--policy`, `--max-moves`, `--seed` and `--desk`
+    pub fn from_args(args: &[&str]) -> Result<Self, String> {
+        let mut config = BalanceConfig {
+            width: 7,
+            height: 4,
+            armies: vec![],
+            games: 1000,
+            policies: (Policy::Greedy, Policy::Greedy),
+            max_moves: DEFAULT_MAX_MOVES,
+            seed: 1,
+        };
+        for pair in args.chunks(2) {
+            let [flag, value] = pair else {
+                return Err(format!("`{}` needs a value", pair[0]));
+            };
+            let number = |value: &str| value.parse::<u64>().map_err(|_| format!("{} expects a number, got `{}`", flag, value));
+            match *flag {
+                "--games" => config.games = u32::try_from(number(value)?).map_err(|_| format!("--games can be at most {}", u32::MAX))?,
+                "--max-moves" => config.max_moves = u16::try_from(number(value)?).map_err(|_| format!("--max-moves can be at most {}", u16::MAX))?,
+                "--seed" => config.seed = number(value)?,
+                "--armies" => config.armies = value.split(',').map(parse_army).collect::<Result<_, _>>()?,
+                "--policy" => config.policies = match value.split_once(',') {
+                    Some((first, second)) => (parse_policy(first)?, parse_policy(second)?),
+                    None => (parse_policy(value)?, parse_policy(value)?),
+                },
+                "--desk" => {
+                    let (width, height) = value.split_once('x').ok_or_else(|| format!("--desk expects WIDTHxHEIGHT, got `{}`", value))?;
+                    config.width = width.parse().map_err(|_| format!("`{}` is not a width", width))?;
+                    config.height = height.parse().map_err(|_| format!("`{}` is not a height", height))?;
+                }
+                _ => return Err(format!("unknown option `{}`", flag)),
+            }
+        }
+        if config.armies.is_empty() {
+            config.armies = ["OOO", "MMM", "SSS", "OMS"].iter().map(|a| parse_army(a)).collect::<Result<_, _>>()?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.width < 2 || self.height < 1 || self.width as i16 * self.height as i16 > MAX_BOARD_CELLS as i16 {
+            return Err(format!("desk must have at least 2 columns and at most {} cells", MAX_BOARD_CELLS));
+        }
+        let half = (self.width / 2) as usize * self.height as usize;
+        if let Some(army) = self.armies.iter().find(|army| army.len() > half) {
+            return Err(format!("army `{}` doesn't fit on a half of the desk", army_name(army)));
+        }
+        if self.games == 0 {
+            return Err("--games must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// plays `games` games for every ordered pair of armies, mirror matches included
+pub fn run(config: &BalanceConfig) -> BalanceReport {
+    let mut rng = Rng::new(config.seed);
+    let mut matchups = vec![];
+    for first in 0..config.armies.len() {
+        for second in 0..config.armies.len() {
+            let mut stats = MatchupStats::default();
+            for _ in 0..config.games {
+                let (result, moves) = play_game(config, &config.armies[first], &config.armies[second], &mut rng);
+                match result {
+                    GameResult::FirstWins => stats.first_wins += 1,
+                    GameResult::SecondWins => stats.second_wins += 1,
+                    GameResult::Draw => stats.draws += 1,
+                }
+                stats.total_moves += moves as u64;
+            }
+            matchups.push((first, second, stats));
+        }
+    }
+    BalanceReport { matchups }
+}
+
+fn play_game(config: &BalanceConfig, first_army: &[i8], second_army: &[i8], rng: &mut Rng) -> (GameResult, u16) {
+    let mut game: Game<u8> = Game::new(0, config.width, config.height).expect("desk size is checked by `from_args`");
+    game.join(1).expect("new game can be joined");
+    for (player, army) in [(0, first_army), (1, second_army)] {
+        let units = random_placement(&game, player, army, rng);
+        game.place_units(units, player).expect("placement is on the player's half");
+    }
+    while game.move_count < config.max_moves {
+        let Some(player) = game.move_owner() else {
+            break;
+        };
+        let policy = if player == 0 { config.policies.0 } else { config.policies.1 };
+        let Some((unit_id, moves)) = choose_move(&game, player, policy, rng) else {
+            break;
+        };
+        game.make_move(unit_id, moves, player).expect("legal moves are accepted");
+    }
+    let result = match game.winner {
+        Some(0) => GameResult::FirstWins,
+        Some(_) => GameResult::SecondWins,
+        None => GameResult::Draw,
+    };
+    (result, game.move_count)
+}
+
+/// random free cells of the player's half of the desk
+fn random_placement(game: &Game<u8>, player: u8, army: &[i8], rng: &mut Rng) -> Vec<(i8, i8, i8)> {
+    let (width, height) = (game.desk.width(), game.desk.height());
+    let columns = if player == 0 { 0..width / 2 } else { width - width / 2..width };
+    let mut cells: Vec<(i8, i8)> = columns.flat_map(|x| (0..height).map(move |y| (x, y))).collect();
+    army.iter().map(|&unit_type| {
+        let (x, y) = cells.swap_remove(rng.below(cells.len()));
+        (unit_type, x, y)
+    }).collect()
+}
+
+fn choose_move(game: &Game<u8>, player: u8, policy: Policy, rng: &mut Rng) -> Option<(i8, Vec<(i8, i8)>)> {
+    let mut moves = game.legal_moves_of(&player);
+    if moves.is_empty() {
+        return None;
+    }
+    if policy == Policy::Greedy {
+        let scores: Vec<f32> = moves.iter()
+            .map(|(unit_id, chain)| game.score_move(*unit_id, chain, &player).unwrap_or(f32::MIN))
+            .collect();
+        let best = scores.iter().cloned().fold(f32::MIN, f32::max);
+        moves = moves.into_iter().zip(scores).filter(|(_, s)| *s == best).map(|(m, _)| m).collect();
+    }
+    let index = rng.below(moves.len());
+    Some(moves.swap_remove(index))
+}
+
+impl BalanceReport {
+    pub fn render(&self, config: &BalanceConfig) -> String {
+        let mut out = format!("{} games per matchup on {}x{}, policies {:?} vs {:?}, seed {}\n\n",
+            config.games, config.width, config.height, config.policies.0, config.policies.1, config.seed);
+        out.push_str(&format!("{:<8}{:<8}{:>12}{:>13}{:>8}{:>11}\n", "first", "second", "first wins", "second wins", "draws", "avg moves"));
+        let percent = |n: u32, of: u32| 100.0 * n as f64 / of.max(1) as f64;
+        for (first, second, stats) in &self.matchups {
+            out.push_str(&format!("{:<8}{:<8}{:>11.1}%{:>12.1}%{:>7.1}%{:>11.1}\n",
+                army_name(&config.armies[*first]), army_name(&config.armies[*second]),
+                percent(stats.first_wins, stats.games()), percent(stats.second_wins, stats.games()),
+                percent(stats.draws, stats.games()), stats.average_moves()));
+        }
+
+        out.push_str(&format!("\n{:<8}{:>8}{:>11}\n", "army", "games", "win rate"));
+        for (i, army) in config.armies.iter().enumerate() {
+            let (mut wins, mut games) = (0, 0);
+            for (first, second, stats) in &self.matchups {
+                if first == second {
+                    continue;
+                }
+                if *first == i {
+                    wins += stats.first_wins;
+                    games += stats.games();
+                }
+                if *second == i {
+                    wins += stats.second_wins;
+                    games += stats.games();
+                }
+            }
+            out.push_str(&format!("{:<8}{:>8}{:>10.1}%\n", army_name(army), games, percent(wins, games)));
+        }
+
+        let total = self.matchups.iter().fold(MatchupStats::default(), |mut total, (_, _, stats)| {
+            total.first_wins += stats.first_wins;
+            total.second_wins += stats.second_wins;
+            total.draws += stats.draws;
+            total.total_moves += stats.total_moves;
+            total
+        });
+        out.push_str(&format!("\nfirst mover wins {:.1}%, second {:.1}%, draws {:.1}%, average game {:.1} moves\n",
+            percent(total.first_wins, total.games()), percent(total.second_wins, total.games()),
+            percent(total.draws, total.games()), total.average_moves()));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn balance_runs_are_reproducible() {
+        let args = ["--armies", "OS,mm", "--games", "20", "--policy", "greedy,random", "--seed", "7"];
+        let config = BalanceConfig::from_args(&args).unwrap();
+        assert_eq!(config.armies, vec![vec![1, 2], vec![0, 0]]);
+        let report = run(&config);
+        assert_eq!(report.matchups.len(), 4);
+        assert!(report.matchups.iter().all(|(_, _, stats)| stats.games() == 20 && stats.average_moves() >= 1.0));
+        assert_eq!(run(&config).matchups, report.matchups);
+        assert!(report.render(&config).contains("first mover wins"));
+
+        assert!(BalanceConfig::from_args(&["--armies", "OOOOOOO"]).is_err());
+        assert!(BalanceConfig::from_args(&["--desk", "2x2", "--armies", "OOO"]).is_err());
+        assert_eq!(BalanceConfig::from_args(&["--max-moves", "65536", "--armies", "OOO"]).err(), Some("--max-moves can be at most 65535".to_string()));
+        assert_eq!(BalanceConfig::from_args(&["--games", "4294967296", "--armies", "OOO"]).err(), Some("--games can be at most 4294967295".to_string()));
+        assert!(BalanceConfig::from_args(&["--policy", "smart"]).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+//! Terminal simulator of HopChain battles, built on the rules of `hopchain-engine`.
+//!
+//! `hopchain-sim play [width height]` plays a battle between two players at one terminal,
+//! `hopchain-sim replay <file>` replays recorded `place_units` / `make_move` calls from a JSON file
+//! or a battle written in the text notation.
+//! Both print the desk, damage, HP of hit units and the winner after every step.
+//! `hopchain-sim balance [options]` plays armies against each other many times and reports win rates.
+
+mod balance;
+
+use hopchain_engine::{Game, GameError, MoveOutcome, Notation, Record, UnitType};
+use serde::Deserialize;
+use std::io::{self, BufRead, Write};
+use std::process::ExitCode;
+
+const DEFAULT_WIDTH: i8 = 7;
+const DEFAULT_HEIGHT: i8 = 4;
+
+/// Recorded battle, calls take the same arguments as the contract methods
+#[derive(Deserialize)]
+struct Replay {
+    #[serde(default = "default_width")]
+    width: i8,
+    #[serde(default = "default_height")]
+    height: i8,
+    /// creator of the battle and the player who joined it
+    players: (String, String),
+    calls: Vec<Call>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Call {
+    PlaceUnits { player: String, units: Vec<(i8, i8, i8)> },
+    MakeMove { player: String, unit_id: i8, moves: Vec<(i8, i8)> },
+}
+
+fn default_width() -> i8 {
+    DEFAULT_WIDTH
+}
+
+fn default_height() -> i8 {
+    DEFAULT_HEIGHT
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["replay", path] => run_replay(path),
+        ["balance", options @ ..] => balance::BalanceConfig::from_args(options).map(|config| {
+            print!("{}", balance::run(&config).render(&config));
+        }),
+        ["play"] => play(DEFAULT_WIDTH, DEFAULT_HEIGHT),
+        ["play", width, height] => match (width.parse(), height.parse()) {
+            (Ok(width), Ok(height)) => play(width, height),
+            _ => Err("width and height must be numbers".to_string()),
+        },
+        _ => Err("usage: hopchain-sim play [width height] | hopchain-sim replay <file.json | file.hop> | \
+            hopchain-sim balance [--armies OOS,MMS] [--games 1000] [--policy greedy|random[,greedy|random]] \
+            [--desk 7x4] [--max-moves 200] [--seed 1]".to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn new_game(first: &str, second: &str, width: i8, height: i8) -> Result<Game<String>, String> {
+    if width < 2 {
+        return Err("desk must have at least 2 columns".to_string());
+    }
+    let mut game = Game::new(first.to_string(), width, height).map_err(describe_error)?;
+    game.join(second.to_string()).map_err(describe_error)?;
+    Ok(game)
+}
+
+fn run_replay(path: &str) -> Result<(), String> {
+    let file = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let replay = if path.ends_with(".json") {
+        serde_json::from_str(&file).map_err(|e| format!("{}: {}", path, e))?
+    }
+    else {
+        let notation = Notation::parse(&file).map_err(|e| format!("{}: {}", path, e))?;
+        replay_of_notation(&notation)?
+    };
+    let mut out = String::new();
+    let result = replay_calls(&replay, &mut out);
+    print!("{}", out);
+    result
+}
+
+/// turns records into calls, unit ids are found by playing the records on a scratch game,
+/// a record the rules reject fails the whole notation
+fn replay_of_notation(notation: &Notation) -> Result<Replay, String> {
+    let (width, height) = notation.desk_size().map_err(|e| e.to_string())?;
+    let players = (notation.tag("First").unwrap_or("first").to_string(), notation.tag("Second").unwrap_or("second").to_string());
+    let mut game = new_game(&players.0, &players.1, width, height)?;
+    let mut calls = vec![];
+    for (i, record) in notation.records.iter().enumerate() {
+        let rejected = |e| format!("record #{} is illegal: {}", i + 1, describe_error(e));
+        match record {
+            Record::Placement { first_player, units } => {
+                let player = if *first_player { &players.0 } else { &players.1 };
+                game.place_units(units.clone(), player.clone()).map_err(rejected)?;
+                calls.push(Call::PlaceUnits { player: player.clone(), units: units.clone() });
+            }
+            Record::Move { first_player, moves, .. } => {
+                let player = if *first_player { &players.0 } else { &players.1 };
+                let unit_id = moves.first().and_then(|&(x, y)| game.desk.cell(x, y)).and_then(|c| c.unit).map_or(-1, |u| u.unit_id);
+                game.make_move(unit_id, moves.clone(), player.clone()).map_err(rejected)?;
+                calls.push(Call::MakeMove { player: player.clone(), unit_id, moves: moves.clone() });
+            }
+        }
+    }
+    Ok(Replay { width, height, players, calls })
+}
+
+/// plays every call of the replay, stops at the first one the rules reject
+fn replay_calls(replay: &Replay, out: &mut String) -> Result<(), String> {
+    let (first, second) = &replay.players;
+    let mut game = new_game(first, second, replay.width, replay.height)?;
+    out.push_str(&render(&game));
+    for (i, call) in replay.calls.iter().enumerate() {
+        let step = i + 1;
+        match call {
+            Call::PlaceUnits { player, units } => {
+                out.push_str(&format!("\n#{} {} place_units {:?}\n", step, player, units));
+                game.place_units(units.clone(), player.clone())
+                    .map_err(|e| format!("call #{} failed: {}", step, describe_error(e)))?;
+            }
+            Call::MakeMove { player, unit_id, moves } => {
+                out.push_str(&format!("\n#{} {} make_move unit {} {:?}\n", step, player, unit_id, moves));
+                let outcome = game.make_move(*unit_id, moves.clone(), player.clone())
+                    .map_err(|e| format!("call #{} failed: {}", step, describe_error(e)))?;
+                out.push_str(&describe_outcome(&outcome));
+            }
+        }
+        out.push_str(&render(&game));
+    }
+    if game.winner.is_none() {
+        out.push_str("\nbattle is not finished\n");
+    }
+    Ok(())
+}
+
+fn play(width: i8, height: i8) -> Result<(), String> {
+    let mut lines = io::stdin().lock().lines();
+    let first = ask(&mut lines, "first player name: ").filter(|n| !n.is_empty()).unwrap_or_else(|| "first".to_string());
+    let second = ask(&mut lines, "second player name: ").filter(|n| !n.is_empty()).unwrap_or_else(|| "second".to_string());
+    if first == second {
+        return Err("players must have different names".to_string());
+    }
+    let mut game = new_game(&first, &second, width, height)?;
+    println!("unit types: mage (0), orc (1), skeleton (2)");
+    for player in [&first, &second] {
+        print!("{}", render(&game));
+        loop {
+            let Some(line) = ask(&mut lines, &format!("{} places units as `type x y, ...`: ", player)) else {
+                return Ok(());
+            };
+            // a rejected call is retried on the game as it was before it
+            let mut next = game.clone();
+            match parse_units(&line).and_then(|units| next.place_units(units, player.clone()).map_err(describe_error)) {
+                Ok(()) => {
+                    game = next;
+                    break;
+                }
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+    print!("{}", render(&game));
+    while let Some(player) = game.move_owner() {
+        let Some(line) = ask(&mut lines, &format!("{} moves as `unit_id x,y x,y ...` or quits: ", player)) else {
+            return Ok(());
+        };
+        if line == "quit" {
+            return Ok(());
+        }
+        let mut next = game.clone();
+        match parse_move(&line).and_then(|(unit_id, moves)| next.make_move(unit_id, moves, player.clone()).map_err(describe_error)) {
+            Ok(outcome) => {
+                game = next;
+                print!("{}", describe_outcome(&outcome));
+                print!("{}", render(&game));
+            }
+            Err(e) => println!("{}", e),
+        }
+    }
+    Ok(())
+}
+
+/// prints the prompt and reads a trimmed line, `None` once the input is closed
+fn ask(lines: &mut impl Iterator<Item = io::Result<String>>, prompt: &str) -> Option<String> {
+    print!("{}", prompt);
+    io::stdout().flush().ok()?;
+    lines.next()?.ok().map(|line| line.trim().to_string())
+}
+
+/// parses `orc 1 0, mage 2 1` into `(unit_type, x, y)` like `place_units` takes them
+fn parse_units(line: &str) -> Result<Vec<(i8, i8, i8)>, String> {
+    line.split(',').map(|unit| {
+        let parts: Vec<&str> = unit.split_whitespace().collect();
+        let [unit_type, x, y] = parts.as_slice() else {
+            return Err(format!("`{}` is not `type x y`", unit.trim()));
+        };
+        let unit_type = match unit_type.to_lowercase().as_str() {
+            "mage" | "m" => 0,
+            "orc" | "o" => 1,
+            "skeleton" | "s" => 2,
+            other => other.parse().map_err(|_| format!("unknown unit type `{}`", other))?,
+        };
+        Ok((unit_type, parse_number(x)?, parse_number(y)?))
+    }).collect()
+}
+
+/// parses `0 1,0 3,0` into the unit id and the positions of its hop chain
+fn parse_move(line: &str) -> Result<(i8, Vec<(i8, i8)>), String> {
+    let mut parts = line.split_whitespace();
+    let unit_id = parse_number(parts.next().unwrap_or_default())?;
+    let moves = parts.map(|position| {
+        let (x, y) = position.split_once(',').ok_or_else(|| format!("`{}` is not `x,y`", position))?;
+        Ok((parse_number(x)?, parse_number(y)?))
+    }).collect::<Result<Vec<_>, String>>()?;
+    Ok((unit_id, moves))
+}
+
+fn parse_number(s: &str) -> Result<i8, String> {
+    s.trim().parse().map_err(|_| format!("`{}` is not a number", s.trim()))
+}
+
+fn describe_error(e: GameError) -> String {
+    match e {
+        GameError::NotYourBattle => "player is not in this battle",
+        GameError::NotYourTurn => "it is not this player's turn",
+        GameError::NotYourUnit => "unit doesn't belong to this player or doesn't stand on the first cell",
+        GameError::IllegalHop => "hop chain breaks the rules",
+        GameError::CellOccupied => "cell is occupied",
+        GameError::OutOfBounds => "position is out of the desk",
+        GameError::WrongState => "battle is not in the right state for that",
+        GameError::UnknownUnitType => "unknown unit type",
+        GameError::UnitNotFound => "unit not found",
+        GameError::BattleFull => "battle is full",
+        GameError::TooManyUnits => "player can have at most 6 units",
+        GameError::PlacementNotAllowed => "units must be placed on the player's half of the desk",
+        GameError::InvalidDeskSize => "desk must have at least 1 row and at most 64 cells",
+    }.to_string()
+}
+
+fn unit_name(unit_type: UnitType) -> &'static str {
+    match unit_type {
+        UnitType::Orc => "orc",
+        UnitType::Mage => "mage",
+        UnitType::Skeleton => "skeleton",
+    }
+}
+
+/// draws the desk with the highest row on top, units of the first player are in upper case
+fn render(game: &Game<String>) -> String {
+    let desk = &game.desk;
+    let mut out = String::from("\n   ");
+    for x in 0..desk.width() {
+        out.push_str(&format!("{:>3}", x));
+    }
+    out.push('\n');
+    for y in (0..desk.height()).rev() {
+        out.push_str(&format!("{:>3}", y));
+        for x in 0..desk.width() {
+            let symbol = match desk.cell(x, y).and_then(|c| c.unit) {
+                Some(unit) => {
+                    let letter = unit_name(unit.unit_type).chars().next().unwrap_or('?');
+                    if game.first_player_units.contains(&unit.unit_id) { letter.to_ascii_uppercase() } else { letter }
+                }
+                None => '.',
+            };
+            out.push_str(&format!("{:>3}", symbol));
+        }
+        out.push('\n');
+    }
+    for (unit_id, entry) in &game.units {
+        let unit = entry.unit;
+        out.push_str(&format!("  unit {} {} {} hp {}/{} at ({}, {})\n", unit_id, entry.owner, unit_name(unit.unit_type),
+            unit.stats.hp, unit.stats.max_hp, unit.current_position.x, unit.current_position.y));
+    }
+    out
+}
+
+fn describe_outcome(outcome: &MoveOutcome<String>) -> String {
+    let (x, y) = outcome.final_position;
+    let mut out = format!("unit {} landed at ({}, {}) and deals {} damage (x{})\n",
+        outcome.unit_id, x, y, outcome.damage, outcome.damage_multiplier);
+    for damaged in &outcome.damaged_units {
+        let killed = if outcome.killed_units.contains(&damaged.unit_id) { ", killed" } else { "" };
+        out.push_str(&format!("  unit {} hp {} -> {}{}\n", damaged.unit_id, damaged.hp_before, damaged.hp_after, killed));
+    }
+    match (&outcome.winner, &outcome.next_move_owner) {
+        (Some(winner), _) => out.push_str(&format!("{} wins the battle\n", winner)),
+        (None, Some(next)) => out.push_str(&format!("{} moves next\n", next)),
+        (None, None) => {}
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_battle_is_replayed() {
+        let replay: Replay = serde_json::from_str(include_str!("../replays/first_blood.json")).unwrap();
+        let mut out = String::new();
+        replay_calls(&replay, &mut out).unwrap();
+        assert!(out.contains("unit 2 hp 12 -> 0, killed"));
+        assert!(out.contains("alice wins the battle"));
+        assert!(out.contains("  0  .  .  O  S  .  .  ."));
+
+        let illegal: Replay = serde_json::from_str(r#"{"players": ["alice", "bob"], "calls": [
+            {"method": "place_units", "player": "bob", "units": [[1, 0, 0]]}]}"#).unwrap();
+        assert_eq!(replay_calls(&illegal, &mut String::new()).err(),
+            Some("call #1 failed: units must be placed on the player's half of the desk".to_string()));
+
+        let notation = Notation::parse("[First \"alice\"]\n[Second \"bob\"]\nS@b1 O@c1\nm@e1\n1. S b1-d1").unwrap();
+        let mut from_notation = String::new();
+        replay_calls(&replay_of_notation(&notation).unwrap(), &mut from_notation).unwrap();
+        assert_eq!(from_notation, out);
+
+        let illegal = Notation::parse("[First \"alice\"]\n[Second \"bob\"]\nS@b1 O@c1\nm@e1\n1. S b1-c1").unwrap();
+        assert_eq!(replay_of_notation(&illegal).err(), Some("record #3 is illegal: cell is occupied".to_string()));
+    }
+
+    #[test]
+    fn terminal_input_is_parsed_into_call_arguments() {
+        assert_eq!(parse_units("orc 1 0, M 2 1,2 0 3"), Ok(vec![(1, 1, 0), (0, 2, 1), (2, 0, 3)]));
+        assert!(parse_units("orc 1").is_err() && parse_units("dragon 1 0").is_err());
+        assert_eq!(parse_move("0 1,0 3,0 3,2"), Ok((0, vec![(1, 0), (3, 0), (3, 2)])));
+        assert!(parse_move("0 1;0").is_err() && parse_move("").is_err());
+    }
+}
@@ -0,0 +1,256 @@
+//! Spectator bets: anyone but the players and their sponsors can back a side of a battle until
+//! both armies are placed. Every battle has its own parimutuel pool, bettors on the winning side
+//! share the whole pool by their stakes.
+
+use super::*;
+
+/// stake times pool doesn't fit in u128
+mod wide {
+    #![allow(clippy::all)]
+    uint::construct_uint! {
+        pub struct U256(4);
+    }
+}
+use wide::U256;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Side {
+    FirstPlayer,
+    SecondPlayer,
+}
+
+/// NEAR staked on each side, by one bettor or by everybody in the pool of a battle
+#[derive(BorshDeserialize, BorshSerialize, Default, Clone, Copy)]
+pub struct Stakes {
+    first_player: Balance,
+    second_player: Balance,
+}
+
+impl Stakes {
+    fn on(&self, side: Side) -> Balance {
+        match side {
+            Side::FirstPlayer => self.first_player,
+            Side::SecondPlayer => self.second_player,
+        }
+    }
+
+    fn add(&mut self, side: Side, amount: Balance) {
+        match side {
+            Side::FirstPlayer => self.first_player += amount,
+            Side::SecondPlayer => self.second_player += amount,
+        }
+    }
+
+    fn total(&self) -> Balance {
+        self.first_player + self.second_player
+    }
+}
+
+/// Stakes as they are returned by `get_bets`, described by `schema/views.schema.json`
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BetsView {
+    pub first_player: U128,
+    pub second_player: U128,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Stakes `amount` of NEAR on `side` of the battle, bets are taken until both armies are placed
+    /// and never on practice battles.
+    /// Attached deposit must cover the stake and storage, everything above that is credited
+    /// to the caller's balance. Bets are in NEAR in token battles too.
+    #[payable]
+    #[handle_result]
+    pub fn place_bet(&mut self, battle_id: u32, side: Side, amount: U128) -> Result<(), HopChainError> {
+        self.assert_not_paused()?;
+        let battle = self.get_battle(battle_id)?;
+        let bettor = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        if battle.game.current_state >= 2 || battle.is_practice() {
+            return Err(HopChainError::WrongState);
+        }
+        if battle.is_participant(&bettor) {
+            return Err(HopChainError::Unauthorized);
+        }
+        if amount.0 == 0 {
+            return Err(HopChainError::BidOutOfLimits);
+        }
+        if deposit < amount.0 {
+            return Err(HopChainError::InsufficientDeposit(amount.0));
+        }
+        let initial_storage = env::storage_usage();
+        let key = (battle_id, bettor.clone());
+        let mut stakes = self.bets.get(&key).unwrap_or_default();
+        stakes.add(side, amount.0);
+        self.bets.insert(&key, &stakes);
+        let mut pool = self.bet_pools.get(&battle_id).unwrap_or_default();
+        pool.add(side, amount.0);
+        self.bet_pools.insert(&battle_id, &pool);
+        self.settle_storage(&bettor, initial_storage, deposit - amount.0)?;
+        Self::emit_event("bet_placed", serde_json::json!({
+            "battle_id": battle_id,
+            "account_id": bettor,
+            "side": side,
+            "amount": amount
+        }));
+        Ok(())
+    }
+
+    /// Settles the caller's bets once the battle has ended and credits the result to the caller's balance.
+    /// Stakes on the winner get their share of the whole pool, the platform fee is taken only from
+    /// the winnings over the stake. Stakes on the loser are lost. A draw, a cancelled battle or a winner nobody backed refunds every stake.
+    /// Returns the credited amount.
+    #[handle_result]
+    pub fn claim_bet(&mut self, battle_id: u32) -> Result<U128, HopChainError> {
+        let winning_side = self.winning_side(battle_id)?;
+        let bettor = env::predecessor_account_id();
+        let key = (battle_id, bettor.clone());
+        let stakes = self.bets.get(&key).ok_or(HopChainError::Unauthorized)?;
+        let pool = self.bet_pools.get(&battle_id).unwrap_or_default();
+        let initial_storage = env::storage_usage();
+        self.bets.remove(&key);
+        let credited = match winning_side {
+            Some(side) if pool.on(side) > 0 => {
+                let share = (U256::from(stakes.on(side)) * U256::from(pool.total()) / U256::from(pool.on(side))).as_u128();
+                let fee = self.collect_fee(share - stakes.on(side), &None);
+                self.internal_deposit(&bettor, &None, share - fee);
+                share - fee
+            },
+            _ => {
+                self.internal_deposit(&bettor, &None, stakes.total());
+                stakes.total()
+            }
+        };
+        self.settle_storage(&bettor, initial_storage, 0)?;
+        Self::emit_event("bet_claimed", serde_json::json!({
+            "battle_id": battle_id,
+            "account_id": bettor,
+            "amount": U128(credited)
+        }));
+        Ok(U128(credited))
+    }
+
+    /// stakes of `account_id` on the battle, or of the whole pool without `account_id`
+    pub fn get_bets(&self, battle_id: u32, account_id: Option<AccountId>) -> Option<BetsView> {
+        let stakes = match account_id {
+            Some(account_id) => self.bets.get(&(battle_id, account_id)),
+            None => self.bet_pools.get(&battle_id)
+        };
+        stakes.map(|s| BetsView { first_player: U128(s.first_player), second_player: U128(s.second_player) })
+    }
+}
+
+impl Contract {
+    /// side of the winner of an ended battle, `None` for a draw or a cancelled battle,
+    /// `WrongState` while the battle is played
+    fn winning_side(&self, battle_id: u32) -> Result<Option<Side>, HopChainError> {
+        let (first_player, winner) = match self.get_battle(battle_id) {
+            Ok(battle) if battle.game.current_state >= 3 => (battle.game.first_player, battle.game.winner),
+            Ok(_) => return Err(HopChainError::WrongState),
+            Err(_) => {
+                let summary = self.archived_battles.get(&battle_id).ok_or(HopChainError::BattleNotFound)?;
+                (summary.first_player, summary.winner)
+            }
+        };
+        Ok(winner.map(|winner| if Some(winner) == first_player { Side::FirstPlayer } else { Side::SecondPlayer }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{account, call, json_keys, resolve, schema_properties, sponsored_battle};
+    use near_sdk::{testing_env, ONE_NEAR};
+
+    #[test]
+    fn spectators_share_the_pool_of_the_winning_side() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 1000);
+        let battle_id = sponsored_battle(&mut contract);
+        for participant in ["alice.near", "relayer.near"] {
+            testing_env!(call(participant, 2 * ONE_NEAR).build());
+            assert_eq!(contract.place_bet(battle_id, Side::SecondPlayer, U128(ONE_NEAR)).err(), Some(HopChainError::Unauthorized));
+        }
+        testing_env!(call("carol.near", 4 * ONE_NEAR).build());
+        contract.place_bet(battle_id, Side::FirstPlayer, U128(3 * ONE_NEAR)).unwrap();
+        testing_env!(call("dave.near", 2 * ONE_NEAR).build());
+        contract.place_bet(battle_id, Side::SecondPlayer, U128(ONE_NEAR)).unwrap();
+        assert_eq!(contract.claim_bet(battle_id).err(), Some(HopChainError::WrongState));
+
+        testing_env!(call("alice.near", 0).build());
+        contract.place_units(battle_id, vec![(2, 1, 0), (1, 2, 0)], None).unwrap();
+        testing_env!(call("bob.near", 0).build());
+        contract.place_units(battle_id, vec![(0, 4, 0)], None).unwrap();
+        testing_env!(call("erin.near", 2 * ONE_NEAR).build());
+        assert_eq!(contract.place_bet(battle_id, Side::FirstPlayer, U128(ONE_NEAR)).err(), Some(HopChainError::WrongState));
+        testing_env!(call("alice.near", 0).build());
+        contract.make_move(battle_id, 0, vec![(1, 0), (3, 0)], None).unwrap();
+
+        let pool = contract.get_bets(battle_id, None).unwrap();
+        assert_eq!((pool.first_player, pool.second_player), (U128(3 * ONE_NEAR), U128(ONE_NEAR)));
+        assert_eq!(json_keys(&serde_json::to_value(&pool).unwrap()), schema_properties("BetsView"));
+        // the whole pool of 4 NEAR minus 10% fee on the 1 NEAR won over the stake
+        let treasury = contract.get_treasury(None).0;
+        testing_env!(call("carol.near", 0).build());
+        assert_eq!(contract.claim_bet(battle_id).unwrap(), U128(39 * ONE_NEAR / 10));
+        assert_eq!(contract.get_treasury(None).0 - treasury, ONE_NEAR / 10);
+        assert_eq!(contract.claim_bet(battle_id).err(), Some(HopChainError::Unauthorized));
+        testing_env!(call("dave.near", 0).build());
+        assert_eq!(contract.claim_bet(battle_id).unwrap(), U128(0));
+
+        // bettors can't join, stakes on a cancelled battle are refunded
+        testing_env!(call("alice.near", ONE_NEAR).build());
+        let battle_id = contract.create_battle(U128(DEFAULT_MIN_BID), None).unwrap();
+        testing_env!(call("carol.near", 2 * ONE_NEAR).build());
+        contract.place_bet(battle_id, Side::SecondPlayer, U128(ONE_NEAR)).unwrap();
+        testing_env!(call("carol.near", ONE_NEAR).build());
+        assert_eq!(contract.join_battle(battle_id).err(), Some(HopChainError::Unauthorized));
+        testing_env!(call("alice.near", 0).build());
+        contract.cancel_battle(battle_id).unwrap();
+        testing_env!(call("carol.near", 0).build());
+        assert_eq!(contract.claim_bet(battle_id).unwrap(), U128(ONE_NEAR));
+
+        // the player of a practice battle decides both sides
+        testing_env!(call("alice.near", ONE_NEAR).build());
+        let battle_id = contract.create_battle(U128(0), Some(Practice::default())).unwrap();
+        testing_env!(call("carol.near", 2 * ONE_NEAR).build());
+        assert_eq!(contract.place_bet(battle_id, Side::SecondPlayer, U128(ONE_NEAR)).err(), Some(HopChainError::WrongState));
+    }
+
+    #[test]
+    fn stakes_are_refunded_after_a_draw_or_an_unbacked_winner() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 1000);
+        let joined_battle = |contract: &mut Contract| {
+            testing_env!(call("alice.near", ONE_NEAR).build());
+            let battle_id = contract.create_battle(U128(DEFAULT_MIN_BID), None).unwrap();
+            testing_env!(call("bob.near", ONE_NEAR).build());
+            contract.join_battle(battle_id).unwrap();
+            battle_id
+        };
+        let draw = joined_battle(&mut contract);
+        let unbacked = joined_battle(&mut contract);
+        testing_env!(call("carol.near", 2 * ONE_NEAR).build());
+        contract.place_bet(draw, Side::FirstPlayer, U128(ONE_NEAR)).unwrap();
+        testing_env!(call("carol.near", 2 * ONE_NEAR).build());
+        contract.place_bet(draw, Side::SecondPlayer, U128(ONE_NEAR)).unwrap();
+        testing_env!(call("dave.near", 3 * ONE_NEAR).build());
+        contract.place_bet(draw, Side::FirstPlayer, U128(2 * ONE_NEAR)).unwrap();
+        testing_env!(call("carol.near", 2 * ONE_NEAR).build());
+        contract.place_bet(unbacked, Side::FirstPlayer, U128(ONE_NEAR)).unwrap();
+        resolve(&mut contract, draw, BattleOutcome::Draw);
+        resolve(&mut contract, unbacked, BattleOutcome::SecondPlayerWins);
+
+        // refunds are whole stakes, no fee is taken
+        testing_env!(call("carol.near", 0).build());
+        assert_eq!(contract.claim_bet(draw).unwrap(), U128(2 * ONE_NEAR));
+        assert_eq!(contract.claim_bet(unbacked).unwrap(), U128(ONE_NEAR));
+        assert_eq!(contract.claim_bet(draw).err(), Some(HopChainError::Unauthorized));
+        testing_env!(call("dave.near", 0).build());
+        assert_eq!(contract.claim_bet(draw).unwrap(), U128(2 * ONE_NEAR));
+        assert_eq!(contract.claim_bet(unbacked).err(), Some(HopChainError::Unauthorized));
+        assert_eq!(contract.get_treasury(None), U128(DEFAULT_MIN_BID * 2 / 10));
+    }
+}
@@ -0,0 +1,275 @@
+//! Doubling the stake like the backgammon doubling cube: a player offers on their turn and puts up
+//! their share of the raise, the opponent either matches it and owns the right to redouble,
+//! or declines and loses the battle at the current stake.
+
+use super::*;
+
+#[near_bindgen]
+impl Contract {
+    /// Offers the opponent to double the bid, attached deposit must cover the current bid
+    /// and the storage, the rest is credited to the caller's balance. Allowed on the caller's turn
+    /// when nobody has doubled yet or the caller accepted the last double.
+    #[payable]
+    #[handle_result]
+    pub fn offer_double(&mut self, battle_id: u32) -> Result<(), HopChainError> {
+        self.assert_not_paused()?;
+        let player = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+        let raise = self.internal_offer_double(battle_id, player.clone(), deposit, None)?;
+        self.settle_battle_storage(battle_id, &player, initial_storage, deposit - raise)
+    }
+
+    /// Accepts the double offered by the opponent, attached deposit must cover the current bid
+    /// and the storage. The bid of both players is doubled and the caller may redouble later.
+    #[payable]
+    #[handle_result]
+    pub fn accept_double(&mut self, battle_id: u32) -> Result<(), HopChainError> {
+        self.assert_not_paused()?;
+        let player = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+        let raise = self.internal_accept_double(battle_id, player.clone(), deposit, None)?;
+        self.settle_battle_storage(battle_id, &player, initial_storage, deposit - raise)
+    }
+
+    /// Declines the double offered by the opponent: the opponent wins the pot at the current bid
+    /// and gets back the deposit of the offer.
+    #[handle_result]
+    pub fn decline_double(&mut self, battle_id: u32) -> Result<(), HopChainError> {
+        self.assert_not_paused()?;
+        let player = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let mut battle = self.get_battle(battle_id)?;
+        let offerer = Self::double_offer_to(&battle, &player)?;
+        battle.double_offered_by = None;
+        battle.game.winner = Some(offerer.clone());
+        battle.game.current_state = 3;
+        battle.ended_at = Some(env::block_timestamp());
+        self.save_battle(&battle);
+        self.finish_battle(battle_id);
+        self.internal_deposit(&offerer, &battle.token, battle.bid);
+        self.pay_out_pot(offerer.clone(), battle.bid * 2, battle.token.clone());
+        Self::emit_event("double_declined", serde_json::json!({ "battle_id": battle_id, "winner": offerer, "bid": U128(battle.bid) }));
+        self.settle_battle_storage(battle_id, &player, initial_storage, 0)
+    }
+
+    /// Takes back the caller's double the opponent hasn't answered, the deposit of the offer goes
+    /// back to the caller's balance. Without a move timeout this is the only way out of an offer
+    /// that is never answered.
+    #[handle_result]
+    pub fn withdraw_double(&mut self, battle_id: u32) -> Result<(), HopChainError> {
+        self.assert_not_paused()?;
+        let player = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let mut battle = self.get_battle(battle_id)?;
+        if battle.double_offered_by.as_ref() != Some(&player) {
+            return Err(HopChainError::WrongState);
+        }
+        self.refund_double_offer(&mut battle);
+        battle.last_action_at = env::block_timestamp();
+        self.save_battle(&battle);
+        Self::emit_event("double_withdrawn", serde_json::json!({ "battle_id": battle_id, "by": player }));
+        self.settle_battle_storage(battle_id, &player, initial_storage, 0)
+    }
+}
+
+impl Contract {
+    /// returns the raise taken from `deposit`, which must be in the battle's token
+    pub(crate) fn internal_offer_double(&mut self, battle_id: u32, player: AccountId, deposit: Balance, token: Option<AccountId>) -> Result<Balance, HopChainError> {
+        let mut battle = self.get_battle(battle_id)?;
+        if battle.token != token {
+            return Err(HopChainError::WrongToken);
+        }
+        if battle.game.first_player.as_ref() != Some(&player) && battle.game.second_player.as_ref() != Some(&player) {
+            return Err(HopChainError::NotYourBattle);
+        }
+        // free play and battles without a bid have nothing to double
+        if battle.game.current_state != 2 || battle.bid == 0 || battle.double_offered_by.is_some()
+            || battle.first_player_sponsor.is_some() || battle.second_player_sponsor.is_some() {
+            return Err(HopChainError::WrongState);
+        }
+        if battle.game.move_owner().as_ref() != Some(&player) {
+            return Err(HopChainError::NotYourTurn);
+        }
+        if battle.cube_owner.as_ref().is_some_and(|owner| owner != &player) {
+            return Err(HopChainError::Unauthorized);
+        }
+        self.assert_bid_in_limits(battle.bid * 2)?;
+        if deposit < battle.bid {
+            return Err(HopChainError::InsufficientDeposit(battle.bid));
+        }
+        battle.double_offered_by = Some(player.clone());
+        battle.last_action_at = env::block_timestamp();
+        self.save_battle(&battle);
+        Self::emit_event("double_offered", serde_json::json!({ "battle_id": battle_id, "by": player, "bid": U128(battle.bid * 2) }));
+        Ok(battle.bid)
+    }
+
+    /// returns the raise taken from `deposit`, which must be in the battle's token
+    pub(crate) fn internal_accept_double(&mut self, battle_id: u32, player: AccountId, deposit: Balance, token: Option<AccountId>) -> Result<Balance, HopChainError> {
+        let mut battle = self.get_battle(battle_id)?;
+        if battle.token != token {
+            return Err(HopChainError::WrongToken);
+        }
+        Self::double_offer_to(&battle, &player)?;
+        let raise = battle.bid;
+        if deposit < raise {
+            return Err(HopChainError::InsufficientDeposit(raise));
+        }
+        battle.bid *= 2;
+        battle.cube_owner = Some(player.clone());
+        battle.double_offered_by = None;
+        battle.last_action_at = env::block_timestamp();
+        self.save_battle(&battle);
+        Self::emit_event("double_accepted", serde_json::json!({ "battle_id": battle_id, "by": player, "bid": U128(battle.bid) }));
+        Ok(raise)
+    }
+
+    /// player who offered `player` the pending double
+    fn double_offer_to(battle: &Battle, player: &AccountId) -> Result<AccountId, HopChainError> {
+        if battle.game.first_player.as_ref() != Some(player) && battle.game.second_player.as_ref() != Some(player) {
+            return Err(HopChainError::NotYourBattle);
+        }
+        match &battle.double_offered_by {
+            Some(offerer) if offerer != player => Ok(offerer.clone()),
+            _ => Err(HopChainError::WrongState)
+        }
+    }
+
+    /// gives the deposit of a double nobody answered back to the player who offered it
+    pub(crate) fn refund_double_offer(&mut self, battle: &mut Battle) {
+        if let Some(offerer) = battle.double_offered_by.take() {
+            self.internal_deposit(&offerer, &battle.token, battle.bid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{account, call, ft_transfer_call, resolve};
+    use near_sdk::{testing_env, ONE_NEAR};
+
+    #[test]
+    fn doubled_stake_is_paid_out_when_a_double_is_declined() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        testing_env!(call("alice.near", 2 * ONE_NEAR).build());
+        let battle_id = contract.create_battle(U128(ONE_NEAR), None).unwrap();
+        testing_env!(call("bob.near", 2 * ONE_NEAR).build());
+        contract.join_battle(battle_id).unwrap();
+        testing_env!(call("alice.near", 0).build());
+        contract.place_units(battle_id, vec![(2, 1, 0), (1, 2, 0)], None).unwrap();
+        testing_env!(call("bob.near", 0).build());
+        contract.place_units(battle_id, vec![(0, 4, 0), (1, 5, 0)], None).unwrap();
+
+        testing_env!(call("bob.near", 2 * ONE_NEAR).build());
+        assert_eq!(contract.offer_double(battle_id).err(), Some(HopChainError::NotYourTurn));
+        testing_env!(call("alice.near", ONE_NEAR / 2).build());
+        assert_eq!(contract.offer_double(battle_id).err(), Some(HopChainError::InsufficientDeposit(ONE_NEAR)));
+        testing_env!(call("alice.near", 2 * ONE_NEAR).build());
+        contract.offer_double(battle_id).unwrap();
+        testing_env!(call("alice.near", 0).build());
+        assert_eq!(contract.make_move(battle_id, 0, vec![(1, 0), (3, 0)], None).err(), Some(HopChainError::WrongState));
+        testing_env!(call("bob.near", 2 * ONE_NEAR).build());
+        contract.accept_double(battle_id).unwrap();
+        let view = contract.get_battle_info(battle_id).unwrap();
+        assert_eq!((view.bid, view.cube_owner, view.double_offered_by), (U128(2 * ONE_NEAR), Some(account("bob.near")), None));
+
+        // only bob may redouble now
+        testing_env!(call("alice.near", 3 * ONE_NEAR).build());
+        assert_eq!(contract.offer_double(battle_id).err(), Some(HopChainError::Unauthorized));
+        testing_env!(call("alice.near", 0).build());
+        contract.make_move(battle_id, 0, vec![(1, 0), (3, 0)], None).unwrap();
+        testing_env!(call("bob.near", 3 * ONE_NEAR).build());
+        contract.offer_double(battle_id).unwrap();
+
+        let balance = contract.get_balance(account("bob.near"), None).0;
+        testing_env!(call("alice.near", 0).build());
+        contract.decline_double(battle_id).unwrap();
+        let battle = contract.get_battle(battle_id).unwrap();
+        assert_eq!((battle.game.winner, battle.bid), (Some(account("bob.near")), 2 * ONE_NEAR));
+        // pot of the doubled bids and the deposit of the declined offer
+        assert_eq!(contract.get_balance(account("bob.near"), None).0 - balance, 6 * ONE_NEAR);
+    }
+
+    #[test]
+    fn token_battles_are_doubled_with_transferred_tokens() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        contract.allow_token(account("usdc.near")).unwrap();
+        let bid = DEFAULT_MIN_BID;
+        for player in ["alice.near", "bob.near"] {
+            testing_env!(call(player, ONE_NEAR).build());
+            contract.deposit();
+        }
+        let transfer = |contract: &mut Contract, sender: &str, amount: u128, msg: &str| ft_transfer_call(contract, "usdc.near", sender, amount, msg);
+        transfer(&mut contract, "alice.near", bid, r#"{"action": "create_battle"}"#).unwrap();
+        let battle_id = contract.last_player_battle_id.get(&account("alice.near")).unwrap();
+        assert_eq!(transfer(&mut contract, "bob.near", 2 * bid, &format!(r#"{{"action": "join_battle", "battle_id": {}}}"#, battle_id)), Ok(bid));
+        testing_env!(call("alice.near", 0).build());
+        contract.place_units(battle_id, vec![(2, 1, 0), (1, 2, 0)], None).unwrap();
+        testing_env!(call("bob.near", 0).build());
+        contract.place_units(battle_id, vec![(0, 4, 0), (1, 5, 0)], None).unwrap();
+
+        let offer = format!(r#"{{"action": "offer_double", "battle_id": {}}}"#, battle_id);
+        testing_env!(call("alice.near", ONE_NEAR).build());
+        assert_eq!(contract.offer_double(battle_id).err(), Some(HopChainError::WrongToken));
+        assert_eq!(transfer(&mut contract, "alice.near", bid / 2, &offer), Err(HopChainError::InsufficientDeposit(bid)));
+        assert_eq!(transfer(&mut contract, "alice.near", 2 * bid, &offer), Ok(bid));
+        testing_env!(call("alice.near", 0).build());
+        contract.withdraw_double(battle_id).unwrap();
+        assert_eq!(contract.get_balance(account("alice.near"), Some(account("usdc.near"))), U128(bid));
+
+        assert_eq!(transfer(&mut contract, "alice.near", bid, &offer), Ok(0));
+        let accept = format!(r#"{{"action": "accept_double", "battle_id": {}}}"#, battle_id);
+        assert_eq!(transfer(&mut contract, "bob.near", bid, &accept), Ok(0));
+        assert_eq!(contract.get_battle_info(battle_id).unwrap().bid, U128(2 * bid));
+        resolve(&mut contract, battle_id, BattleOutcome::FirstPlayerWins);
+        assert_eq!(contract.get_balance(account("alice.near"), Some(account("usdc.near"))), U128(5 * bid));
+        assert_eq!(contract.get_balance(account("bob.near"), Some(account("usdc.near"))), U128(0));
+    }
+
+    #[test]
+    fn unanswered_double_is_withdrawn_or_claimed_as_declined() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        contract.set_config(Config { move_timeout: U64(100), ..Config::default() }).unwrap();
+        testing_env!(call("alice.near", 2 * ONE_NEAR).build());
+        let battle_id = contract.create_battle(U128(ONE_NEAR), None).unwrap();
+        testing_env!(call("bob.near", 2 * ONE_NEAR).build());
+        contract.join_battle(battle_id).unwrap();
+        testing_env!(call("alice.near", 0).build());
+        contract.place_units(battle_id, vec![(2, 1, 0), (1, 2, 0)], None).unwrap();
+        testing_env!(call("bob.near", 0).build());
+        contract.place_units(battle_id, vec![(0, 4, 0), (1, 5, 0)], None).unwrap();
+
+        testing_env!(call("alice.near", 2 * ONE_NEAR).block_timestamp(10).build());
+        contract.offer_double(battle_id).unwrap();
+        let balance = contract.get_balance(account("alice.near"), None).0;
+        testing_env!(call("bob.near", 0).block_timestamp(20).build());
+        assert_eq!(contract.withdraw_double(battle_id).err(), Some(HopChainError::WrongState));
+        testing_env!(call("alice.near", 0).block_timestamp(20).build());
+        contract.withdraw_double(battle_id).unwrap();
+        // the deposit of the offer and the storage it took
+        let credited = contract.get_balance(account("alice.near"), None).0 - balance;
+        assert!((ONE_NEAR..ONE_NEAR + ONE_NEAR / 100).contains(&credited));
+        assert_eq!(contract.get_battle_info(battle_id).unwrap().double_offered_by, None);
+        assert_eq!(contract.withdraw_double(battle_id).err(), Some(HopChainError::WrongState));
+
+        testing_env!(call("alice.near", 2 * ONE_NEAR).block_timestamp(30).build());
+        contract.offer_double(battle_id).unwrap();
+        let balance = contract.get_balance(account("alice.near"), None).0;
+        // bob has the turn to answer, the offer times out in his disfavour
+        testing_env!(call("bob.near", 0).block_timestamp(200).build());
+        assert_eq!(contract.claim_timeout(battle_id).err(), Some(HopChainError::Unauthorized));
+        testing_env!(call("alice.near", 0).block_timestamp(200).build());
+        contract.claim_timeout(battle_id).unwrap();
+        let battle = contract.get_battle(battle_id).unwrap();
+        assert_eq!((battle.game.winner, battle.bid, battle.double_offered_by), (Some(account("alice.near")), ONE_NEAR, None));
+        // pot at the undoubled bid and the deposit of the offer, minus storage of the finished battle
+        let credited = contract.get_balance(account("alice.near"), None).0 - balance;
+        assert!(credited <= 3 * ONE_NEAR && credited > 3 * ONE_NEAR - ONE_NEAR / 100);
+    }
+}
@@ -1,266 +1,434 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::serde::Serialize;
-use near_sdk::{log, near_bindgen, BorshStorageKey, AccountId, Promise};
+use near_sdk::json_types::{U128, U64};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{ext_contract, is_promise_success, log, near_bindgen, serde_json, BorshStorageKey, AccountId, FunctionError, Gas, PanicOnDefault, Promise, PromiseOrValue, PublicKey};
+use std::collections::BTreeMap;
+mod betting;
+mod doubling;
+mod tournament;
+pub use betting::{BetsView, Side, Stakes};
+pub use tournament::{Bracket, Tournament, TournamentPlayerView, TournamentState, TournamentView};
 
-#[derive(Copy, Clone, BorshDeserialize, BorshSerialize, PartialEq, PartialOrd, Eq, Ord, Serialize)]
-pub struct Vector2 {
-    x: i8,
-    y: i8,
-    id: i8,
+use hopchain_engine::{bot_army, Cell, Desk, Game, GameError, Notation, Record, Unit, UnitEntry, UnitType, Vector2, MAX_BOARD_CELLS};
+
+/// Every way a contract call can fail. Calls fail with `"<code>: <message>"`,
+/// codes are stable so clients can map them to their own messages.
+#[derive(FunctionError, Debug, Clone, PartialEq)]
+pub enum HopChainError {
+    BattleNotFound,
+    NotYourBattle,
+    NotYourTurn,
+    NotYourUnit,
+    IllegalHop,
+    CellOccupied,
+    OutOfBounds,
+    WrongState,
+    /// yoctoNEAR the attached deposit must cover
+    InsufficientDeposit(Balance),
+    UnknownUnitType,
+    UnitNotFound,
+    BattleFull,
+    TooManyUnits,
+    PlacementNotAllowed,
+    BidOutOfLimits,
+    InsufficientBalance,
+    Unauthorized,
+    Paused,
+    TokenNotAllowed,
+    WrongToken,
+    InvalidMessage,
+    InvalidDelegation,
+    InvalidSession,
+    TimeoutNotReached,
+    InvalidConfig,
+    NoOpenedBattles,
+    TournamentNotFound,
 }
 
-impl Vector2 {
-    pub fn new(x_position: i8, y_position: i8, _id: i8) -> Vector2 {
-        Vector2{x: x_position, y: y_position, id: _id}
+impl HopChainError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            HopChainError::BattleNotFound => "E01",
+            HopChainError::NotYourBattle => "E02",
+            HopChainError::NotYourTurn => "E03",
+            HopChainError::NotYourUnit => "E04",
+            HopChainError::IllegalHop => "E05",
+            HopChainError::CellOccupied => "E06",
+            HopChainError::OutOfBounds => "E07",
+            HopChainError::WrongState => "E08",
+            HopChainError::InsufficientDeposit(_) => "E09",
+            HopChainError::UnknownUnitType => "E10",
+            HopChainError::UnitNotFound => "E11",
+            HopChainError::BattleFull => "E12",
+            HopChainError::TooManyUnits => "E13",
+            HopChainError::PlacementNotAllowed => "E14",
+            HopChainError::BidOutOfLimits => "E15",
+            HopChainError::InsufficientBalance => "E16",
+            HopChainError::Unauthorized => "E17",
+            HopChainError::Paused => "E18",
+            HopChainError::TokenNotAllowed => "E19",
+            HopChainError::WrongToken => "E20",
+            HopChainError::InvalidMessage => "E21",
+            HopChainError::InvalidDelegation => "E22",
+            HopChainError::InvalidSession => "E23",
+            HopChainError::TimeoutNotReached => "E24",
+            HopChainError::InvalidConfig => "E25",
+            HopChainError::NoOpenedBattles => "E26",
+            HopChainError::TournamentNotFound => "E27",
+        }
     }
 }
-#[derive(Copy, Clone, BorshDeserialize, BorshSerialize, Serialize)]
-pub enum UnitType {
-    Orc,
-    Mage,
-    Skeleton
-}
 
-#[derive(Clone, BorshSerialize, BorshDeserialize, Copy, Serialize)]
-pub struct Unit {
-    unit_type: UnitType,
-    level: i8,
-    stats: UnitStats,
-    current_position: Vector2,
-    unit_id: i8,
+impl std::fmt::Display for HopChainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            HopChainError::BattleNotFound => "Battle not found".to_string(),
+            HopChainError::NotYourBattle => "This is not your room!".to_string(),
+            HopChainError::NotYourTurn => "It is not your turn".to_string(),
+            HopChainError::NotYourUnit => "Illegal move! This unit is not yours".to_string(),
+            HopChainError::IllegalHop => "Move was illegal".to_string(),
+            HopChainError::CellOccupied => "Cell is full!".to_string(),
+            HopChainError::OutOfBounds => "Position is out of the desk".to_string(),
+            HopChainError::WrongState => "Battle is in a wrong state for this action".to_string(),
+            HopChainError::InsufficientDeposit(required) => format!("Attached deposit must cover {} yoctoNEAR", required),
+            HopChainError::UnknownUnitType => "Unknown unit type".to_string(),
+            HopChainError::UnitNotFound => "No unit with this unit_id in the desk".to_string(),
+            HopChainError::BattleFull => "Battle is full".to_string(),
+            HopChainError::TooManyUnits => "Max units is 6".to_string(),
+            HopChainError::PlacementNotAllowed => "You cant place unit on this cell".to_string(),
+            HopChainError::BidOutOfLimits => "Bid is out of the allowed limits".to_string(),
+            HopChainError::InsufficientBalance => "Not enough balance".to_string(),
+            HopChainError::Unauthorized => "You are not allowed to call this method".to_string(),
+            HopChainError::Paused => "Contract is paused".to_string(),
+            HopChainError::TokenNotAllowed => "Token is not allowed for bids".to_string(),
+            HopChainError::WrongToken => "Battle bid is held in another token".to_string(),
+            HopChainError::InvalidMessage => "Invalid transfer message".to_string(),
+            HopChainError::InvalidDelegation => "No valid delegation for this battle".to_string(),
+            HopChainError::InvalidSession => "Session key is expired or for another battle".to_string(),
+            HopChainError::TimeoutNotReached => "Opponent still has time to move".to_string(),
+            HopChainError::InvalidConfig => "Invalid config".to_string(),
+            HopChainError::NoOpenedBattles => "No opened battles found".to_string(),
+            HopChainError::TournamentNotFound => "Tournament not found".to_string(),
+        };
+        write!(f, "{}: {}", self.code(), message)
+    }
 }
 
-impl Unit {
-    pub fn new(unit_level: i8, _unit_type: UnitType, id: i8) -> Self {
-        Self {
-            level: unit_level,
-            stats: UnitStats::new(unit_level, _unit_type),
-            unit_type: _unit_type,
-            current_position: Vector2 { x: 0, y: 0, id: -1 },
-            unit_id: id
+impl From<GameError> for HopChainError {
+    fn from(error: GameError) -> Self {
+        match error {
+            GameError::NotYourBattle => HopChainError::NotYourBattle,
+            GameError::NotYourTurn => HopChainError::NotYourTurn,
+            GameError::NotYourUnit => HopChainError::NotYourUnit,
+            GameError::IllegalHop => HopChainError::IllegalHop,
+            GameError::CellOccupied => HopChainError::CellOccupied,
+            GameError::OutOfBounds => HopChainError::OutOfBounds,
+            GameError::WrongState => HopChainError::WrongState,
+            GameError::UnknownUnitType => HopChainError::UnknownUnitType,
+            GameError::UnitNotFound => HopChainError::UnitNotFound,
+            GameError::BattleFull => HopChainError::BattleFull,
+            GameError::TooManyUnits => HopChainError::TooManyUnits,
+            GameError::PlacementNotAllowed => HopChainError::PlacementNotAllowed,
+            // desk sizes come from the config
+            GameError::InvalidDeskSize => HopChainError::InvalidConfig,
         }
     }
+}
 
-    pub fn get_unit_type_by_id(id: i8) -> UnitType {
-        match id {
-            0 => UnitType::Mage,
-            1 => UnitType::Orc,
-            2 => UnitType::Skeleton,
-            _ => panic!("Unknown unit type")
-        }
+/// Desk layout of the first version of the contract, every cell was stored with its position
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct LegacyDesk {
+    cells: Vec<Cell>,
+    x_range: i8,
+    y_range: i8,
+}
+
+impl From<LegacyDesk> for Desk {
+    fn from(d: LegacyDesk) -> Self {
+        Desk::from_cells(d.x_range, d.y_range, d.cells).expect("first version only created 7x4 desks")
     }
 }
 
-#[derive(Clone, BorshSerialize, BorshDeserialize, Copy, Serialize, Debug)]
-pub struct UnitStats {
-    hp: f32,
-    damage: f32,
-    max_hp: f32,
+/// Everything that happened during one move
+pub type MoveOutcome = hopchain_engine::MoveOutcome<AccountId>;
+
+/// What `make_move` returns: the outcome of the player's move with the contract's reply to it
+/// in a practice battle. In JSON the fields of the outcome come first, then `bot_reply`.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PlayedMove {
+    #[serde(flatten)]
+    pub outcome: MoveOutcome,
+    /// `None` when the battle isn't a practice one or the move ended it, and when the contract resigned
+    pub bot_reply: Option<MoveOutcome>,
+}
+
+/// Game with its bid and payments, the rules are played by `Game`
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Battle {
+    battle_id: u32,
+    game: Game<AccountId>,
+    bid: u128,
+    /// NEP-141 contract the bid is held in, `None` for native NEAR
+    token: Option<AccountId>,
+    /// bytes of contract storage paid for by each player
+    first_player_storage: u64,
+    second_player_storage: u64,
+    /// block timestamp of the moment battle was won or cancelled
+    ended_at: Option<u64>,
+    /// block timestamp of the last join, placement or move, used for move timeouts
+    last_action_at: u64,
+    /// accounts that posted the bid and pay storage for players in free play
+    first_player_sponsor: Option<AccountId>,
+    second_player_sponsor: Option<AccountId>,
+    /// player who may offer the next double, `None` until a double is accepted
+    cube_owner: Option<AccountId>,
+    /// player whose double waits for an answer, the contract holds their deposit of the raise
+    double_offered_by: Option<AccountId>,
+    /// account that paid for creating a tournament battle and the bytes it still pays for,
+    /// players of such a battle pay only for their placements and moves
+    creation_payer: Option<AccountId>,
+    creation_storage: u64
 }
 
-impl UnitStats {
-    pub fn new(unit_level: i8, unit_type: UnitType) -> UnitStats {
-        UnitStats {
-            hp: UnitStats::get_unit_hp_by_level(unit_level, unit_type),
-            damage: UnitStats::get_unit_damage_by_level(unit_level, unit_type),
-            max_hp: UnitStats::get_unit_hp_by_level(unit_level, unit_type),
+/// we cant derive Copy for non-primitive types so this is the reason why we implement From trait for our custom struct
+impl From<&Battle> for Battle{
+    fn from(b: &Battle) -> Self {
+        Self { battle_id: b.battle_id, 
+            game: b.game.clone(),
+            bid: b.bid,
+            token: b.token.clone(),
+            first_player_storage: b.first_player_storage,
+            second_player_storage: b.second_player_storage,
+            ended_at: b.ended_at,
+            last_action_at: b.last_action_at,
+            first_player_sponsor: b.first_player_sponsor.clone(),
+            second_player_sponsor: b.second_player_sponsor.clone(),
+            cube_owner: b.cube_owner.clone(),
+            double_offered_by: b.double_offered_by.clone(),
+            creation_payer: b.creation_payer.clone(),
+            creation_storage: b.creation_storage
         }
     }
-    
-    /// returs max hp of unit based on it's level
-    /// # Usage 
-    /// * 'level' - level of the Unit
-    /// * 'unit_type' - Orc, Skeleton or Mage
-    fn get_unit_hp_by_level(level: i8, unit_type: UnitType) -> f32 {
-            match unit_type {
-                UnitType::Mage => 12.0 + 3.0*(level as f32-1.0),
-                UnitType::Orc => match level {
-                    1 => 40.0,
-                    2 => 42.0,
-                    3 => 48.0,
-                    4 => 56.0,
-                    5 => 64.0,
-                    _ => 0.0
-                }
-                UnitType::Skeleton => match level {
-                    1 => 25.0,
-                    2 => 27.0,
-                    3 => 30.0,
-                    4 => 35.0,
-                    5 => 40.0,
-                    _ => 0.0
-                }
-            }
+}
+
+impl Battle {
+
+    pub fn new(_bid: u128, token: Option<AccountId>, creator: AccountId, id: u32, x: i8, y: i8) -> Result<Self, GameError> {
+        Ok(Battle { 
+            battle_id: id, 
+            game: Game::new(creator, x, y)?,
+            bid: _bid,
+            token,
+            first_player_storage: 0,
+            second_player_storage: 0,
+            ended_at: None,
+            last_action_at: 0,
+            first_player_sponsor: None,
+            second_player_sponsor: None,
+            cube_owner: None,
+            double_offered_by: None,
+            creation_payer: None,
+            creation_storage: 0
+        })
+    }
+
+    /// account that paid the bid and storage of `player`: the sponsor in free play, else the player
+    pub fn payer_of(&self, player: &AccountId) -> AccountId {
+        let sponsor = if Some(player) == self.game.first_player.as_ref() {
+            self.first_player_sponsor.clone()
         }
+        else if Some(player) == self.game.second_player.as_ref() {
+            self.second_player_sponsor.clone()
+        }
+        else {
+            None
+        };
+        sponsor.unwrap_or_else(|| player.clone())
+    }
 
-    /// returs damage of unit based on it's level
-    /// # Usage 
-    /// * 'level' - level of the Unit
-    /// * 'unit_type' - Orc, Skeleton or Mage
-    fn get_unit_damage_by_level(level: i8, unit_type: UnitType) -> f32 {
-        match unit_type {
-            UnitType::Mage => 8.0 + 2.0*(level as f32-1.0),
-            UnitType::Orc => match level {
-                1 => 4.0,
-                2 => 6.0,
-                3 => 6.0,
-                4 => 8.0,
-                5 => 8.0,
-                _ => 0.0
-            }
-            UnitType::Skeleton => 10.0 + 2.0*(level as f32-1.0)
+    /// players of the battle and the sponsors paying for them
+    pub fn is_participant(&self, account_id: &AccountId) -> bool {
+        [&self.game.first_player, &self.game.second_player, &self.first_player_sponsor, &self.second_player_sponsor]
+            .iter().any(|a| a.as_ref() == Some(account_id))
+    }
+
+    /// battle against the contract itself, created with `practice`
+    pub fn is_practice(&self) -> bool {
+        self.game.second_player == Some(env::current_account_id())
+    }
+
+    /// records `bytes` of storage paid by `player`
+    pub fn add_storage(&mut self, player: &AccountId, bytes: StorageUsage) {
+        if Some(player) == self.game.first_player.as_ref() {
+            self.first_player_storage += bytes;
+        }
+        else if Some(player) == self.game.second_player.as_ref() {
+            self.second_player_storage += bytes;
         }
     }
 }
 
-///
-/// Implemetation of cell on a board, must contain unit which is on a cell right now
-#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Serialize)]
-pub struct Cell {
-    pub unit: Option<Unit>,
-    pub position: Vector2,
+/// What is left of a battle after it was archived, the desk is deleted.
+/// `U128` and `U64` are stored like plain numbers, so the layout is unchanged.
+#[derive(BorshDeserialize, BorshSerialize, Serialize)]
+pub struct BattleSummary {
+    battle_id: u32,
+    first_player: Option<AccountId>,
+    second_player: Option<AccountId>,
+    bid: U128,
+    token: Option<AccountId>,
+    winner: Option<AccountId>,
+    move_count: u16,
+    ended_at: Option<U64>
 }
 
-impl Cell {
-    pub fn new(x:i8, y: i8, id:i8) -> Self {
-        Cell{unit: None, position: Vector2::new(x,y, id)}
+impl From<&Battle> for BattleSummary {
+    fn from(b: &Battle) -> Self {
+        Self {
+            battle_id: b.battle_id,
+            first_player: b.game.first_player.clone(),
+            second_player: b.game.second_player.clone(),
+            bid: U128(b.bid),
+            token: b.token.clone(),
+            winner: b.game.winner.clone(),
+            move_count: b.game.move_count,
+            ended_at: b.ended_at.map(U64)
+        }
     }
 }
 
-///
-/// Implementation of a desk in a game, contrains a map of all cells on a board.
-/// 
-#[derive(BorshDeserialize, BorshSerialize,BorshStorageKey, Clone, Serialize)]
-pub struct Desk {
-    cells: Vec<Cell>,
-    x_range: i8,
-    y_range: i8,
+/// Battle as it is returned by `get_battle_info`, described by `schema/views.schema.json`
+#[derive(Serialize)]
+pub struct BattleView {
+    pub battle_id: u32,
+    pub first_player: Option<AccountId>,
+    pub second_player: Option<AccountId>,
+    pub bid: U128,
+    pub token: Option<AccountId>,
+    /// -1 waiting for the second player, 0 placing units, 2 playing, 3 finished, 4 cancelled
+    pub state: i8,
+    /// `None` unless the battle is being played
+    pub move_owner: Option<AccountId>,
+    pub winner: Option<AccountId>,
+    pub move_count: u16,
+    pub width: i8,
+    pub height: i8,
+    /// occupied cells only
+    pub cells: Vec<CellView>,
+    pub last_action_at: U64,
+    pub ended_at: Option<U64>,
+    pub cube_owner: Option<AccountId>,
+    pub double_offered_by: Option<AccountId>,
 }
 
-impl Desk {
-    pub fn new(x:i8, y:i8) -> Self {
-        Desk {
-        cells: Self::generate_desk(x, y),
-        x_range: x,
-        y_range: y
-    }
-    }
+/// Occupied cell of the desk with the unit standing on it
+#[derive(Serialize)]
+pub struct CellView {
+    pub x: i8,
+    pub y: i8,
+    pub owner: Option<AccountId>,
+    pub unit_id: i8,
+    pub unit_type: UnitType,
+    pub level: i8,
+    pub hp: f32,
+    pub max_hp: f32,
+}
 
+/// Unit of the registry as it is returned by `get_units`
+#[derive(Serialize)]
+pub struct UnitView {
+    pub unit_id: i8,
+    pub owner: AccountId,
+    pub unit_type: UnitType,
+    pub level: i8,
+    pub hp: f32,
+    pub max_hp: f32,
+    pub damage: f32,
+    pub x: i8,
+    pub y: i8,
+}
 
-    fn generate_desk(x:i8, y:i8) -> Vec<Cell> {
-        let mut cells = vec![];
-        let mut index =0_i8;
-        for i in 0..x {
-            for j in 0..y {
-                cells.push(Cell::new(i, j, index));
-                index+=1;
-            }
+impl From<&UnitEntry<AccountId>> for UnitView {
+    fn from(entry: &UnitEntry<AccountId>) -> Self {
+        Self {
+            unit_id: entry.unit.unit_id,
+            owner: entry.owner.clone(),
+            unit_type: entry.unit.unit_type,
+            level: entry.unit.level,
+            hp: entry.unit.stats.hp,
+            max_hp: entry.unit.stats.max_hp,
+            damage: entry.unit.stats.damage,
+            x: entry.unit.current_position.x,
+            y: entry.unit.current_position.y
         }
-        cells
-    }
-
-    pub fn get_cell_at(&self, x:i8, y:i8) -> Cell {
-        return *self.cells.iter().find(|c| (c.position.x == x && c.position.y == y)).unwrap();
     }
+}
 
-    pub fn insert_unit_at_cell(&mut self, x:i8, y:i8, unit: Option<Unit>) {
-        let index = self.cells.iter().position(|&c| (c.position.x == x && c.position.y == y)).unwrap();
-        let mut cell = self.get_cell_at(x, y);
-        cell.unit = unit;
-        self.cells.push(cell);
-        self.cells.swap_remove(index);
+impl From<&Battle> for BattleView {
+    fn from(b: &Battle) -> Self {
+        Self {
+            battle_id: b.battle_id,
+            first_player: b.game.first_player.clone(),
+            second_player: b.game.second_player.clone(),
+            bid: U128(b.bid),
+            token: b.token.clone(),
+            state: b.game.current_state,
+            move_owner: b.game.move_owner(),
+            winner: b.game.winner.clone(),
+            move_count: b.game.move_count,
+            width: b.game.desk.width(),
+            height: b.game.desk.height(),
+            cells: b.game.desk.cells().iter()
+                .filter_map(|c| c.unit.map(|unit| CellView {
+                    x: c.position.x,
+                    y: c.position.y,
+                    owner: b.game.owner_of(unit.unit_id),
+                    unit_id: unit.unit_id,
+                    unit_type: unit.unit_type,
+                    level: unit.level,
+                    hp: unit.stats.hp,
+                    max_hp: unit.stats.max_hp
+                }))
+                .collect(),
+            last_action_at: U64(b.last_action_at),
+            ended_at: b.ended_at.map(U64),
+            cube_owner: b.cube_owner.clone(),
+            double_offered_by: b.double_offered_by.clone()
+        }
     }
+}
 
-    pub fn get_unit_by_id(&self, unit_id: i8) -> Unit {
-        let cell = self.cells.iter().find(|c|(c.unit.is_some() && c.unit.unwrap().unit_id == unit_id));
-        assert!(cell.is_some(), "No unit with same unit_id has found in this desk");
-        cell.unwrap().unit.unwrap()
-    }
+/// Battle as it is stored, older layouts are converted to `Battle` when they are read
+#[derive(BorshDeserialize, BorshSerialize)]
+#[allow(clippy::large_enum_variant)] // only lives while a battle is read or written
+pub enum VersionedBattle {
+    V1(BattleV1),
+    V2(Battle),
+}
 
-    pub fn get_cells_to_deal_damage(&self, position: Vector2, unit_type: UnitType) -> Vec<Option<&Cell>> {
-        assert!(position.x <= self.x_range && position.x >= 0, "Incorrect position");
-        assert!(position.y <= self.y_range && position.y >= 0, "Incorrect position");
-        match unit_type {
-            UnitType::Orc => {
-                self.get_orc_damage_cells(position)
-            },
-            UnitType::Mage => self.get_mage_damage_cells(position),
-            UnitType::Skeleton => self.get_skeleton_damage_cells(position),
-        }
-    } 
-
-    fn get_orc_damage_cells(&self, position: Vector2) -> Vec<Option<&Cell>> {
-        let cells = vec![self.cells.iter().find(|&c| c.position.x == position.x+1 && c.position.y == position.y),
-        self.cells.iter().find(|&c| c.position.y == position.y+1 && c.position.x == position.x),
-        self.cells.iter().find(|&c| c.position.x == position.x-1 && c.position.y == position.y),
-        self.cells.iter().find(|&c| c.position.y == position.y-1 && c.position.x == position.x),
-        self.cells.iter().find(|&c| c.position.x == position.x+1 && c.position.y == position.y+1),
-        self.cells.iter().find(|&c| c.position.x == position.x-1 && c.position.y == position.y-1),
-        self.cells.iter().find(|&c| c.position.x == position.x-1 && c.position.y == position.y+1),
-        self.cells.iter().find(|&c| c.position.x == position.x+1 && c.position.y == position.y-1)
-        ];
-        cells
-    }
-
-    fn get_mage_damage_cells(&self, position: Vector2) -> Vec<Option<&Cell>> {
-        let cells = vec![self.cells.iter().find(|&c| c.position.x == position.x +1 && c.position.y == position.y),
-        self.cells.iter().find(|&c| c.position.x == position.x +2 && c.position.y == position.y),
-        self.cells.iter().find(|&c| c.position.x == position.x -1 && c.position.y == position.y),
-        self.cells.iter().find(|&c| c.position.x == position.x -2 && c.position.y == position.y),
-        self.cells.iter().find(|&c| c.position.y == position.y +1 && c.position.x == position.x),
-        self.cells.iter().find(|&c| c.position.y == position.y +2 && c.position.x == position.x),
-        self.cells.iter().find(|&c| c.position.y == position.y -1 && c.position.x == position.x),
-        self.cells.iter().find(|&c| c.position.y == position.y -2 && c.position.x == position.x)];
-        cells
-    }
-
-    fn get_skeleton_damage_cells(&self, position: Vector2) -> Vec<Option<&Cell>> {
-        let cells = vec![self.cells.iter().find(|&c| c.position.x == position.x + 1 && c.position.y == position.y),
-        self.cells.iter().find(|&c| c.position.x == position.x - 1 && c.position.y == position.y),
-        self.cells.iter().find(|&c| c.position.y == position.y + 1 && c.position.x == position.x),
-        self.cells.iter().find(|&c| c.position.y == position.y - 1 && c.position.x == position.x)];
-        cells
-    }
-
-    pub fn place_unit(&mut self, position: Vector2, unit: Unit) -> bool {
-        assert!(self.get_cell_at(position.x, position.y).unit.is_none(), "Cell is full!");
-        self.insert_unit_at_cell(position.x, position.y, Some(unit));
-        true
-    }
-
-    fn deal_damage_at_cell(&mut self, x: i8, y:i8, damage: f32) -> i8 {
-        let index = self.cells.iter().position(|&c| (c.position.x == x && c.position.y == y)).unwrap();
-        let mut cell = self.get_cell_at(x, y);
-        let mut unit_to_delete_id = -1;
-        if let Some(u) = &mut cell.unit {
-            u.stats.hp -= damage;
-            if u.stats.hp <= 0.0 {
-                unit_to_delete_id = u.unit_id;
-                cell.unit = None
-                
-            }
+impl From<VersionedBattle> for Battle {
+    fn from(b: VersionedBattle) -> Self {
+        match b {
+            VersionedBattle::V1(b) => Battle::from(b),
+            VersionedBattle::V2(b) => b,
         }
-        self.cells.push(cell);
-        self.cells.swap_remove(index);
-        unit_to_delete_id
     }
 }
 
-impl From<&Desk> for Desk {
-    fn from(d: &Desk) -> Self {
-        Self {
-            cells: d.cells.clone(),
-            x_range: d.x_range,
-            y_range: d.y_range
-        }
+impl From<&Battle> for VersionedBattle {
+    fn from(b: &Battle) -> Self {
+        VersionedBattle::V2(Battle::from(b))
     }
 }
-#[derive(BorshDeserialize, BorshSerialize, Serialize)]
-pub struct Battle {
+
+/// Battle layout of the first version of the contract
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct BattleV1 {
     battle_id: i8,
     first_player: Option<AccountId>,
     second_player: Option<AccountId>,
-    desk: Desk,
+    desk: LegacyDesk,
     bid: u128,
     first_player_units: Vec<i8>,
     second_player_units: Vec<i8>,
@@ -270,355 +438,2122 @@ pub struct Battle {
     winner: Option<AccountId>
 }
 
-/// we cant derive Copy for non-primitive types so this is the reason why we implement From trait for our custom struct
-impl From<&Battle> for Battle{
-    fn from(b: &Battle) -> Self {
-        Self { battle_id: b.battle_id, 
-            first_player: b.first_player.clone(), 
-            second_player: b.second_player.clone(),
-            desk: Desk::from(&b.desk),
-            first_player_units: b.first_player_units.clone(),
-            second_player_units: b.second_player_units.clone(),
-            bid: b.bid,
+impl From<BattleV1> for Battle {
+    fn from(b: BattleV1) -> Self {
+        let mut game = Game {
+            first_player: b.first_player,
+            second_player: b.second_player,
+            desk: Desk::from(b.desk),
+            first_player_units: b.first_player_units,
+            second_player_units: b.second_player_units,
+            units: BTreeMap::new(),
             current_state: b.current_state,
             last_unit_id: b.last_unit_id,
             current_move_owner: b.current_move_owner,
-            winner: b.winner.clone()
+            winner: b.winner,
+            move_count: 0,
+            history: vec![]
+        };
+        game.register_desk_units();
+        // the first version kept no history, units standing on the desk become its setup
+        for first_player in [true, false] {
+            let units: Vec<(i8,i8,i8)> = game.units.values()
+                .filter(|e| (game.first_player.as_ref() == Some(&e.owner)) == first_player)
+                .map(|e| (Unit::get_id_by_unit_type(e.unit.unit_type), e.unit.current_position.x, e.unit.current_position.y))
+                .collect();
+            if !units.is_empty() {
+                game.history.push(Record::Placement { first_player, units });
+            }
+        }
+        Battle {
+            battle_id: b.battle_id as u32,
+            game,
+            bid: b.bid,
+            token: None,
+            first_player_storage: 0,
+            second_player_storage: 0,
+            ended_at: None,
+            last_action_at: env::block_timestamp(),
+            first_player_sponsor: None,
+            second_player_sponsor: None,
+            cube_owner: None,
+            double_offered_by: None,
+            creation_payer: None,
+            creation_storage: 0
         }
     }
 }
 
-impl Battle {
+use near_sdk::collections::{Vector, LookupMap, LookupSet};
+use near_sdk::{env, Balance, StorageUsage};
 
-    pub fn new(_bid: u128, creator: AccountId, id: i8, x: i8, y: i8) -> Self {
-        Battle { 
-            battle_id: id, 
-            first_player: Some(creator), 
-            second_player: None,
-            desk: Desk::new(x, y),
-            first_player_units: vec![],
-            second_player_units: vec![],
-            bid: _bid,
-            current_state: -1,
-            last_unit_id: 0,
-            current_move_owner: 0,
-            winner: None
-        }
+const DEFAULT_MIN_BID: Balance = 10_000_000_000_000_000_000_000;
+const ONE_YOCTO: Balance =1;
+const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_WITHDRAW_CALLBACK: Gas = Gas(10_000_000_000_000);
+const MAX_FEE_BASIS_POINTS: u16 = 10_000;
+const DEFAULT_FIELD: Vector2 = Vector2{x:7, y:4, id:0};
+const EVENT_STANDARD: &str = "hopchain";
+const EVENT_VERSION: &str = "1.0.0";
+/// storage key of the contract state layout version, first version of the contract didn't write it
+const STATE_VERSION_KEY: &[u8] = b"STATE_VERSION";
+const STATE_VERSION: u8 = 2;
+const GAS_FOR_MIGRATE: Gas = Gas(100_000_000_000_000);
+/// gas allowance of a session key, paid by the player who adds it and not returned:
+/// the contract can't tell how much of it failed calls burned
+const SESSION_KEY_ALLOWANCE: Balance = 250_000_000_000_000_000_000_000;
+const SESSION_KEY_METHODS: &str = "make_move,place_units";
+const GAS_FOR_SESSION_CALLBACK: Gas = Gas(20_000_000_000_000);
+/// most hop chains the practice bot scores before replying, each one is played on a copy of the game
+const BOT_MAX_SCORED_MOVES: usize = 48;
+#[derive(BorshSerialize, BorshStorageKey)]
+enum StorageKey {
+    Battles,
+    BattleOfPlayer,
+    LastBattleOfPlayer,
+    OpenedBattles,
+    AllowedTokens,
+    Treasury,
+    Balances,
+    ArchivedBattles,
+    FinishedBattles,
+    Moderators,
+    Delegations,
+    Sessions,
+    BattleSessions,
+    Tournaments,
+    Bets,
+    BetPools,
+    Relayers
+}
+
+/// Permission given by a player to another account to place units and make moves for them
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Delegation {
+    /// `None` allows playing in all battles of the player
+    pub battle_id: Option<u32>,
+    /// block timestamp in nanoseconds after which the delegation is no longer valid
+    pub expires_at: Option<U64>,
+}
+
+/// Function call access key of this contract a player uses to sign moves without a wallet
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Session {
+    pub player: AccountId,
+    pub battle_id: u32,
+    pub expires_at: U64,
+}
+
+impl Delegation {
+    fn allows(&self, battle_id: u32) -> bool {
+        self.battle_id.is_none_or(|id| id == battle_id)
+            && self.expires_at.is_none_or(|expires_at| env::block_timestamp() < expires_at.0)
     }
+}
 
-    pub fn join_battle(&mut self, player_id: AccountId) {
-        assert!(self.second_player.is_none(), "Battle is full");
-        self.second_player = Some(player_id);
-        self.current_state = 0;
-    }
-
-    pub fn make_move(&mut self, unit_id: i8, moves: Vec<(i8,i8)>, caller: AccountId) -> Option<AccountId> {
-        assert!(self.current_state == 2, "Current state must be 2");
-        if Some(caller.clone()) == self.first_player {
-            assert!(self.first_player_units.contains(&unit_id), "Illegal move! This unit is not yours!");
-            assert!(self.current_move_owner == 0, "Current move owner is second player");
-        }
-        else if Some(caller) == self.second_player {
-            assert!(self.second_player_units.contains(&unit_id), "Illegal move! This unit is not yours");
-            assert!(self.current_move_owner == 1, "Current move owner is first player");
-        }
-        let length = moves.len();
-        let (start_x, start_y) = moves.first().unwrap();
-        let unit = self.desk.get_unit_by_id(unit_id);
-        let initial_damage = unit.stats.damage as f32;
-        assert!(self.desk.get_cell_at(*start_x, *start_y).unit.is_some(), "There is no unit on start cell!");
-        assert!(unit_id == (self.desk.get_cell_at(*start_x, *start_y)).unit.unwrap().unit_id);
-        let mut damage = initial_damage;
-        for i in 0..length-1 {
-            let (x1,y1) = moves.get(i).unwrap();
-            let (x2,y2) = moves.get(i+1).unwrap();
-            let pos_1 = Vector2 { x: *x1, y: *y1, id: 100 };
-            let pos_2 = Vector2 { x: *x2, y: *y2, id: 100 };
-            if self.is_valid_move(pos_1, pos_2) {
-                if self.is_unit_between(pos_1, pos_2){
-                    damage = initial_damage * (1_f32+0.5*(i as i8+1) as f32);
-                }
-            }
-            else {
-                panic!("Move was Illegal! {} {} - {} {}", pos_1.x, pos_1.y, pos_2.x, pos_2.y);
-            }
+/// Settings the owner can change without redeploying the contract
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Config {
+    pub min_bid: U128,
+    /// `None` means there is no upper limit
+    pub max_bid: Option<U128>,
+    pub board_width: i8,
+    pub board_height: i8,
+    /// nanoseconds a player may stay idle on their turn before the opponent can claim the win,
+    /// 0 disables timeouts
+    pub move_timeout: U64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            min_bid: U128(DEFAULT_MIN_BID),
+            max_bid: None,
+            board_width: DEFAULT_FIELD.x,
+            board_height: DEFAULT_FIELD.y,
+            move_timeout: U64(0),
         }
-        let (end_x, end_y) = moves.last().unwrap();
-        self.desk.insert_unit_at_cell(*end_x, *end_y, Some(unit));
-        self.desk.insert_unit_at_cell(*start_x, *start_y, None);
-        self.deal_damage(damage, Vector2 { x: *end_x, y: *end_y, id: -10 }, unit.unit_type);
-        if self.current_move_owner == 0 {
-            self.current_move_owner = 1;
-        }
-        else if self.current_move_owner == 1 {
-            self.current_move_owner = 0;
-        }
-        if self.first_player_units.is_empty() {
-            self.winner = Some(self.second_player.clone().unwrap());
-            self.current_state = 3;
-        }
-        else if self.second_player_units.is_empty() {
-            self.winner = Some(self.first_player.clone().unwrap());
-            self.current_state = 3;
-        }
-        self.winner.clone()
-    }
-
-    fn deal_damage(&mut self, damage: f32, cell_position: Vector2, unit_type: UnitType) {
-        let binding = self.desk.clone();
-        let cells = binding.get_cells_to_deal_damage(cell_position, unit_type);
-        cells.iter().for_each(|x| {
-            if x.is_some() && x.unwrap().unit.is_some() {
-                let unit_to_delete = self.desk.deal_damage_at_cell(x.unwrap().position.x, x.unwrap().position.y, damage);
-                if unit_to_delete != -1 {
-                    if self.first_player_units.contains(&unit_to_delete) {
-                        let index = self.first_player_units.iter().position(|&x| x == unit_to_delete);
-                        if index.is_some() {
-                            self.first_player_units.remove(index.unwrap());
-                        }
-                    }
-                    else if self.second_player_units.contains(&unit_to_delete) {
-                        let index = self.second_player_units.iter().position(|&x| x == unit_to_delete);
-                        if index.is_some() {
-                            self.second_player_units.remove(index.unwrap());
-                        }
-                    }
+    }
+}
 
-                }
-            }
-        });
-        //initial_desk_cells.iter().filter(|x| x.position.x == cells.iter().any(f))
-    } 
-
-    fn is_valid_move(&self, pos_1: Vector2, pos_2: Vector2) -> bool {
-        match self.get_move_vector(pos_1, pos_2) {
-            0 => {
-                if self.is_valid_y_move(pos_1.y, pos_2.y, pos_1.x) {
-                    return true
-                }
-                
-            },
-            1 => {
-                if self.is_valid_x_move(pos_1.x, pos_2.x, pos_1.y) {
-                    return true
-                }
-            },
-            _ => panic!("Illegal move!")
+impl Config {
+    /// max bid must not be less than min bid, board must be from 4x2 up to `MAX_BOARD_CELLS` cells
+    fn validate(&self) -> Result<(), HopChainError> {
+        let bids_valid = self.max_bid.is_none_or(|max_bid| max_bid.0 >= self.min_bid.0);
+        let board_valid = self.board_width >= 4 && self.board_height >= 2
+            && self.board_width as i16 * self.board_height as i16 <= MAX_BOARD_CELLS as i16;
+        if !bids_valid || !board_valid {
+            return Err(HopChainError::InvalidConfig);
         }
-        false
+        Ok(())
     }
+}
 
-    fn is_unit_between(&self, pos_1: Vector2, pos_2: Vector2) -> bool {
-        let min_x = std::cmp::min(pos_1.x,pos_2.x);
-        let min_y = std::cmp::min(pos_1.y, pos_2.y);
-        match self.get_move_vector(pos_1, pos_2) {
-            0 => {
-                if self.desk.get_cell_at(pos_1.x, min_y+1).unit.is_some() {
-                    return true
-                }
-                false
-            },
-            1 => {
-                
-                if self.desk.get_cell_at(min_x + 1, pos_1.y).unit.is_some() {
-                    return true
-                }
-                false
-            },
-            _ => panic!("Illegal move!")
+/// Decision of an owner or moderator on a disputed or stuck battle
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(crate = "near_sdk::serde")]
+pub enum BattleOutcome {
+    FirstPlayerWins,
+    SecondPlayerWins,
+    /// both players get their bids back, no fee is taken
+    Draw,
+}
+
+/// Practice battle against the contract itself, requested with `create_battle`
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Practice {
+    /// seed of a random bot army, the fixed army is placed without it
+    pub seed: Option<U64>,
+}
+
+/// NEP-141 interface used to pay out battles held in fungible tokens
+#[ext_contract(ext_ft)]
+pub trait FungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+}
+
+/// `msg` of an `ft_transfer_call` sent to this contract, e.g.
+/// `{"action": "create_battle"}`, `{"action": "join_battle", "battle_id": 0}` or `{"action": "offer_double", "battle_id": 0}`
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde", tag = "action", rename_all = "snake_case")]
+pub enum FtTransferMessage {
+    CreateBattle,
+    JoinBattle { battle_id: u32 },
+    OfferDouble { battle_id: u32 },
+    AcceptDouble { battle_id: u32 },
+}
+
+/// Contract state layout of the first version, only read by `migrate`
+#[derive(BorshDeserialize)]
+#[cfg_attr(test, derive(BorshSerialize))]
+struct ContractV1 {
+    battles: Vector<BattleV1>,
+    #[allow(dead_code)]
+    battles_of_player: LookupMap<AccountId, i8>,
+    opened_battles: Vector<BattleV1>,
+    #[allow(dead_code)]
+    last_player_battle_id: LookupMap<AccountId, i8>,
+}
+
+// Define the contract structure
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct Contract {
+    battles: LookupMap<u32, VersionedBattle>,
+    battles_of_player: LookupMap<AccountId, u32>,
+    opened_battles: Vector<u32>,
+    last_player_battle_id: LookupMap<AccountId, u32>,
+    next_battle_id: u32,
+    /// finished and cancelled battles waiting to be archived by `prune`
+    finished_battles: Vector<u32>,
+    archived_battles: LookupMap<u32, BattleSummary>,
+    allowed_tokens: LookupSet<AccountId>,
+    owner_id: AccountId,
+    /// rake taken from every pot, in 1/100 of a percent
+    fee_basis_points: u16,
+    /// collected fees by token, `None` for native NEAR
+    treasury: LookupMap<Option<AccountId>, Balance>,
+    /// winnings, refunds and overpayments waiting to be withdrawn, by account and token
+    balances: LookupMap<(AccountId, Option<AccountId>), Balance>,
+    moderators: LookupSet<AccountId>,
+    /// blocks creating, joining and playing battles, refunds and withdrawals still work
+    paused: bool,
+    /// block timestamp of the last `unpause`, move timeouts don't run before it
+    resumed_at: u64,
+    config: Config,
+    /// delegations by (player, delegate)
+    delegations: LookupMap<(AccountId, AccountId), Delegation>,
+    sessions: LookupMap<PublicKey, Session>,
+    /// session keys of each battle, deleted when the battle ends
+    battle_sessions: LookupMap<u32, Vec<PublicKey>>,
+    tournaments: LookupMap<u32, Tournament>,
+    next_tournament_id: u32,
+    /// spectator stakes by (battle, bettor), removed when claimed
+    bets: LookupMap<(u32, AccountId), Stakes>,
+    /// all stakes on each battle
+    bet_pools: LookupMap<u32, Stakes>,
+    /// relayers allowed to sponsor free play from their balance
+    relayers: LookupSet<AccountId>,
+}
+
+#[near_bindgen]
+// Implement the contract structure
+impl Contract {
+
+    #[init]
+    pub fn new(owner_id: AccountId, fee_basis_points: u16) -> Self {
+        assert!(fee_basis_points <= MAX_FEE_BASIS_POINTS, "Fee can't be more than 100%");
+        env::storage_write(STATE_VERSION_KEY, &[STATE_VERSION]);
+        Self{
+            battles: LookupMap::new(StorageKey::Battles),
+            battles_of_player: LookupMap::new(StorageKey::BattleOfPlayer),
+            last_player_battle_id: LookupMap::new(StorageKey::LastBattleOfPlayer),
+            opened_battles: Vector::new(StorageKey::OpenedBattles),
+            next_battle_id: 0,
+            finished_battles: Vector::new(StorageKey::FinishedBattles),
+            archived_battles: LookupMap::new(StorageKey::ArchivedBattles),
+            allowed_tokens: LookupSet::new(StorageKey::AllowedTokens),
+            owner_id,
+            fee_basis_points,
+            treasury: LookupMap::new(StorageKey::Treasury),
+            balances: LookupMap::new(StorageKey::Balances),
+            moderators: LookupSet::new(StorageKey::Moderators),
+            paused: false,
+            resumed_at: 0,
+            config: Config::default(),
+            delegations: LookupMap::new(StorageKey::Delegations),
+            sessions: LookupMap::new(StorageKey::Sessions),
+            battle_sessions: LookupMap::new(StorageKey::BattleSessions),
+            tournaments: LookupMap::new(StorageKey::Tournaments),
+            next_tournament_id: 0,
+            bets: LookupMap::new(StorageKey::Bets),
+            bet_pools: LookupMap::new(StorageKey::BetPools),
+            relayers: LookupSet::new(StorageKey::Relayers)
         }
     }
+   
+    /// Called by `upgrade` on the new code, or with `--initFunction` when the first version of
+    /// the contract is redeployed. Migrating from the first version needs `owner_id`,
+    /// all battles are rewritten in the current layout.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate(owner_id: Option<AccountId>) -> Self {
+        let version = env::storage_read(STATE_VERSION_KEY).map(|v| v[0]);
+        let contract = match version {
+            None => Self::migrate_from_v1(owner_id.expect("owner_id is required to migrate from the first version")),
+            Some(STATE_VERSION) => env::state_read().expect("Contract is not initialized"),
+            Some(version) => env::panic_str(&format!("Unknown state version {}", version))
+        };
+        env::storage_write(STATE_VERSION_KEY, &[STATE_VERSION]);
+        contract
+    }
+
+    /// Deploys the contract code passed as raw input of the call and runs `migrate` on it.
+    /// Only owner can upgrade the contract.
+    #[handle_result]
+    pub fn upgrade(&self) -> Result<Promise, HopChainError> {
+        self.assert_owner()?;
+        let code = env::input().ok_or(HopChainError::InvalidMessage)?;
+        Ok(Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call("migrate".to_string(), b"{}".to_vec(), 0, GAS_FOR_MIGRATE))
+    }
+
+    /// layout version of the stored state, 1 for the first version that didn't write it
+    pub fn get_state_version(&self) -> u8 {
+        env::storage_read(STATE_VERSION_KEY).map_or(1, |v| v[0])
+    }
 
-    fn get_move_vector(&self, pos_1: Vector2, pos_2: Vector2) -> i8 {
-        if pos_1.x == pos_2.x {
-            return 0
+    #[payable]
+    #[handle_result]
+    pub fn join_battle(&mut self, battle_id: u32) -> Result<bool, HopChainError> {
+        self.assert_not_paused()?;
+        let player = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        let initial_storage = env::storage_usage();
+        let bid = self.internal_join_battle(battle_id, player.clone(), deposit, None, None)?;
+        self.settle_battle_storage(battle_id, &player, initial_storage, deposit - bid)?;
+        Ok(true)
+    }
+
+    /// Free play: `player` calls it through a meta transaction and the relayer sponsors the battle,
+    /// the bid and storage are paid from the relayer's balance, see `deposit` and `allow_relayer`. Winnings go to
+    /// the winner, refunds and freed storage go back to the sponsor.
+    #[payable]
+    #[handle_result]
+    pub fn create_sponsored_battle(&mut self, bid: U128) -> Result<u32, HopChainError> {
+        self.assert_not_paused()?;
+        self.assert_bid_in_limits(bid.0)?;
+        let (player, sponsor) = self.sponsored_player()?;
+        let initial_storage = env::storage_usage();
+        self.take_balance(&sponsor, bid.0)?;
+        let battle_id = self.internal_create_battle(player.clone(), bid.0, None, Some(sponsor))?;
+        self.settle_battle_storage(battle_id, &player, initial_storage, 0)?;
+        Ok(battle_id)
+    }
+
+    /// Free play: `player` joins `battle_id` through a meta transaction, the relayer pays the bid
+    /// and storage from its balance
+    #[payable]
+    #[handle_result]
+    pub fn join_sponsored_battle(&mut self, battle_id: u32) -> Result<bool, HopChainError> {
+        self.assert_not_paused()?;
+        let (player, sponsor) = self.sponsored_player()?;
+        let initial_storage = env::storage_usage();
+        let bid = self.get_battle(battle_id)?.bid;
+        self.take_balance(&sponsor, bid)?;
+        self.internal_join_battle(battle_id, player.clone(), bid, None, Some(sponsor))?;
+        self.settle_battle_storage(battle_id, &player, initial_storage, 0)?;
+        Ok(true)
+    }
+
+    /// credits the attached deposit to the caller's balance, e.g. for a sponsor of free play.
+    /// A new balance is paid for from the deposit.
+    #[payable]
+    pub fn deposit(&mut self) -> U128 {
+        let account_id = env::predecessor_account_id();
+        self.deposit_storage_refund(&account_id, env::attached_deposit());
+        self.get_balance(account_id, None)
+    }
+
+    /// cancels a battle nobody has joined yet, the bid goes back to the creator's balance,
+    /// or to the sponsor's balance in free play
+    #[handle_result]
+    pub fn cancel_battle(&mut self, battle_id: u32) -> Result<(), HopChainError> {
+        let mut battle = self.get_battle(battle_id)?;
+        let creator = battle.game.first_player.clone().ok_or(HopChainError::WrongState)?;
+        let payer = battle.payer_of(&creator);
+        let caller = env::predecessor_account_id();
+        if caller != creator && caller != payer {
+            return Err(HopChainError::Unauthorized);
         }
-        else if pos_1.y == pos_2.y {
-            return 1
+        if battle.game.current_state != -1 || battle.game.second_player.is_some() {
+            return Err(HopChainError::WrongState);
         }
-        -1
+        let initial_storage = env::storage_usage();
+        battle.game.current_state = 4;
+        battle.ended_at = Some(env::block_timestamp());
+        if let Some(op_battle_index) = self.opened_battles.iter().position(|x| x == battle.battle_id) {
+            self.opened_battles.swap_remove(op_battle_index as u64);
+        }
+        self.save_battle(&battle);
+        self.finish_battle(battle_id);
+        self.internal_deposit(&payer, &battle.token, battle.bid);
+        self.settle_battle_storage(battle_id, &creator, initial_storage, 0)
     }
 
-    fn is_valid_x_move(&self, x_1: i8, x_2: i8, y: i8) -> bool {
-        let max = std::cmp::max(x_1, x_2);
-        let min = std::cmp::min(x_1, x_2);
-        if max - min <= 0 {
-            return false
-        }
-        else if max - min == 2 && self.desk.get_cell_at(min+1, y).unit.is_some() {
-            return true
+    /// sends `amount` of the caller's balance to the caller, `token_id` is `None` for NEAR.
+    /// Balance is restored if the transfer fails.
+    #[handle_result]
+    pub fn withdraw(&mut self, amount: U128, token_id: Option<AccountId>) -> Result<Promise, HopChainError> {
+        let account_id = env::predecessor_account_id();
+        let available = self.balances.get(&(account_id.clone(), token_id.clone())).unwrap_or(0);
+        if amount.0 == 0 || amount.0 > available {
+            return Err(HopChainError::InsufficientBalance);
         }
-        if max - min == 1 {
+        self.balances.insert(&(account_id.clone(), token_id.clone()), &(available - amount.0));
+        Ok(self.transfer_pot(account_id.clone(), amount.0, token_id.clone())
+            .then(Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_WITHDRAW_CALLBACK)
+                .on_withdraw(account_id, amount, token_id)))
+    }
+
+    #[private]
+    pub fn on_withdraw(&mut self, account_id: AccountId, amount: U128, token_id: Option<AccountId>) -> bool {
+        if is_promise_success() {
             return true;
         }
+        log!("Withdrawal of {} to {} failed, restoring balance", amount.0, account_id);
+        self.internal_deposit(&account_id, &token_id, amount.0);
         false
     }
 
-    fn is_valid_y_move(&self, y_1: i8, y_2:i8, x: i8) -> bool {
-        let max = std::cmp::max(y_1,y_2);
-        let min = std::cmp::min(y_1, y_2);
-        if max - min <= 0 {
-            return false
+    pub fn get_balance(&self, account_id: AccountId, token_id: Option<AccountId>) -> U128 {
+        U128(self.balances.get(&(account_id, token_id)).unwrap_or(0))
+    }
+
+    /// storage taken by placed units is paid from the attached deposit or the player's balance,
+    /// `player` is set when a delegate places units on behalf of that player
+    #[payable]
+    #[handle_result]
+    pub fn place_units(&mut self, battle_id: u32, units: Vec<(i8,i8,i8)>, player: Option<AccountId>) -> Result<(), HopChainError> {
+        self.assert_not_paused()?;
+        let player = self.resolve_player(battle_id, player)?;
+        let deposit = self.deposit_of(&player);
+        let initial_storage = env::storage_usage();
+        let mut battle = self.get_battle(battle_id)?;
+        battle.game.place_units(units, player.clone())?;
+        battle.last_action_at = env::block_timestamp();
+        self.save_battle(&battle);
+        self.settle_battle_storage(battle_id, &player, initial_storage, deposit)
+    }
+
+    #[private]
+    fn get_battle(&self, battle_id: u32) -> Result<Battle, HopChainError> {
+        self.battles.get(&battle_id).map(Battle::from).ok_or(HopChainError::BattleNotFound)
+    }
+
+    fn save_battle(&mut self, battle: &Battle) {
+        self.battles.insert(&battle.battle_id, &VersionedBattle::from(battle));
+    }
+    
+    /// returns bid and id of an opened battle, not necessarily the oldest one:
+    /// cancelled and joined battles are swapped out of the list
+    #[handle_result]
+    pub fn find_battle(&self) -> Result<(U128, u32), HopChainError> {
+        let battle_id = self.opened_battles.get(0).ok_or(HopChainError::NoOpenedBattles)?;
+        let battle = self.get_battle(battle_id)?;
+        Ok((U128(battle.bid), battle.battle_id))
+    }
+
+    /// storage freed by killed units is credited back to whoever paid for it, storage of the winner's
+    /// balance is paid by the mover. `player` is set when a delegate moves on behalf of that player.
+    /// In a practice battle the contract replies right away, its move is returned as `bot_reply` and
+    /// logged as a `bot_moved` event, `next_move_owner` of the reply is the player again.
+    /// When the contract has no legal move it resigns with a `bot_resigned` event and the player wins.
+    #[payable]
+    #[handle_result]
+    pub fn make_move(&mut self, battle_id: u32, unit_id: i8, moves: Vec<(i8,i8)>, player: Option<AccountId>) -> Result<PlayedMove, HopChainError> {
+        self.assert_not_paused()?;
+        let player = self.resolve_player(battle_id, player)?;
+        let deposit = self.deposit_of(&player);
+        let initial_storage = env::storage_usage();
+        let mut battle = self.get_battle(battle_id)?;
+        if battle.double_offered_by.is_some() {
+            return Err(HopChainError::WrongState);
         }
-        else if max-min == 2 && self.desk.get_cell_at(x, min+1).unit.is_some() {
-            return true
+        let owners: BTreeMap<i8, AccountId> = battle.game.units.iter().map(|(unit_id, entry)| (*unit_id, entry.owner.clone())).collect();
+        let mut outcome = battle.game.make_move(unit_id, moves, player.clone())?;
+        let bot = env::current_account_id();
+        let reply = if outcome.next_move_owner.as_ref() == Some(&bot) { Self::play_bot_move(&mut battle, &bot, &mut outcome) } else { None };
+        let last_outcome = reply.as_ref().unwrap_or(&outcome);
+        battle.last_action_at = env::block_timestamp();
+        if last_outcome.battle_ended {
+            battle.ended_at = Some(env::block_timestamp());
+            self.finish_battle(battle_id);
         }
-        if max - min == 1 {
-            return true;
+        self.save_battle(&battle);
+        if let Some(winner) = last_outcome.winner.clone() {
+            self.pay_out_pot(winner, battle.bid * 2, battle.token);
         }
-        false
+        // freed storage goes back to the owners of the killed units, by both moves in practice
+        let killed_owners: Vec<AccountId> = outcome.killed_units.iter().chain(reply.iter().flat_map(|r| r.killed_units.iter()))
+            .filter_map(|unit_id| owners.get(unit_id).cloned())
+            .collect();
+        self.settle_battle_storage_freed_by(battle_id, &player, &killed_owners, initial_storage, deposit)?;
+        Ok(PlayedMove { outcome, bot_reply: reply })
     }
 
-    pub fn place_units(&mut self, units: Vec<(i8,i8,i8)>, caller: AccountId) {
-        assert!(self.current_state == 0, "Incorrect state!");
-        assert!(units.len() <= 6, "Max units is 6");
-        if Some(caller.clone()) == self.first_player {
-            assert!(self.first_player_units.len() + units.len() <=6, "Max units is 6!");
+    /// creates a battle with a stake of `bid`, attached deposit must cover the bid and
+    /// the storage of the new battle, everything above that is credited back to the creator.
+    /// With `practice` the contract joins as the second player and places its army right away,
+    /// `bid` must be 0 then.
+    #[payable]
+    #[handle_result]
+    pub fn create_battle(&mut self, bid: U128, practice: Option<Practice>) -> Result<u32, HopChainError> {
+        self.assert_not_paused()?;
+        match practice {
+            Some(_) if bid.0 != 0 => return Err(HopChainError::BidOutOfLimits),
+            Some(_) => {},
+            None => self.assert_bid_in_limits(bid.0)?
         }
-        else if Some(caller.clone()) == self.second_player {
-            assert!(self.second_player_units.len() + units.len() <= 6, "Max units is 6!");
+        let creator = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        if deposit < bid.0 {
+            return Err(HopChainError::InsufficientDeposit(bid.0));
         }
-
-        assert!(Some(caller.clone()) == self.first_player || Some(caller.clone()) == self.second_player, "This is not your room!");
-        for unit in units {
-        
-        let (unit_type, x, y) = unit;
-        let new_unit = Unit::new(1, Unit::get_unit_type_by_id(unit_type), self.last_unit_id);
-        if Some(caller.clone()) == self.first_player {
-            assert!(x <= 2, "you cant place unit on this cell");
-            self.first_player_units.push(self.last_unit_id);
+        let initial_storage = env::storage_usage();
+        let battle_id = self.internal_create_battle(creator.clone(), bid.0, None, None)?;
+        if let Some(practice) = practice {
+            let bot = env::current_account_id();
+            self.internal_join_battle(battle_id, bot.clone(), 0, None, None)?;
+            let mut battle = self.get_battle(battle_id)?;
+            let army = bot_army(battle.game.desk.width(), battle.game.desk.height(), practice.seed.map(|seed| seed.0));
+            battle.game.place_units(army, bot)?;
+            self.save_battle(&battle);
         }
-        else {
-            assert!(x < self.desk.x_range && x>= 4, "you cant place unit on this cell");
-            self.second_player_units.push(self.last_unit_id);
+        self.settle_battle_storage(battle_id, &creator, initial_storage, deposit - bid.0)?;
+        Ok(battle_id)
+    }
+
+    /// NEP-141 receiver: creates or joins a battle with the transferred tokens as the bid,
+    /// or offers or accepts a double of a token battle.
+    /// Tokens over the bid of the joined battle or over the raise are returned to the sender.
+    /// Storage of the battle is paid from the sender's NEAR balance in the contract.
+    #[handle_result]
+    pub fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> Result<PromiseOrValue<U128>, HopChainError> {
+        self.assert_not_paused()?;
+        let token_id = env::predecessor_account_id();
+        if !self.allowed_tokens.contains(&token_id) {
+            return Err(HopChainError::TokenNotAllowed);
         }
-        self.last_unit_id +=1;
-        let pos = Vector2::new(x,y,0);
-        self.desk.place_unit(pos, new_unit);
-        if !(self.first_player_units.is_empty()) && !(self.second_player_units.is_empty()) {
-            self.current_state = 2;
+        if amount.0 == 0 {
+            return Err(HopChainError::BidOutOfLimits);
         }
+        let message: FtTransferMessage = serde_json::from_str(&msg).map_err(|_| HopChainError::InvalidMessage)?;
+        let initial_storage = env::storage_usage();
+        match message {
+            FtTransferMessage::CreateBattle => {
+                let battle_id = self.internal_create_battle(sender_id.clone(), amount.0, Some(token_id), None)?;
+                self.settle_battle_storage(battle_id, &sender_id, initial_storage, 0)?;
+                Ok(PromiseOrValue::Value(U128(0)))
+            },
+            FtTransferMessage::JoinBattle { battle_id } => {
+                let bid = self.internal_join_battle(battle_id, sender_id.clone(), amount.0, Some(token_id), None)?;
+                self.settle_battle_storage(battle_id, &sender_id, initial_storage, 0)?;
+                Ok(PromiseOrValue::Value(U128(amount.0 - bid)))
+            },
+            FtTransferMessage::OfferDouble { battle_id } => {
+                let raise = self.internal_offer_double(battle_id, sender_id.clone(), amount.0, Some(token_id))?;
+                self.settle_battle_storage(battle_id, &sender_id, initial_storage, 0)?;
+                Ok(PromiseOrValue::Value(U128(amount.0 - raise)))
+            },
+            FtTransferMessage::AcceptDouble { battle_id } => {
+                let raise = self.internal_accept_double(battle_id, sender_id.clone(), amount.0, Some(token_id))?;
+                self.settle_battle_storage(battle_id, &sender_id, initial_storage, 0)?;
+                Ok(PromiseOrValue::Value(U128(amount.0 - raise)))
+            }
         }
     }
-}
 
+    #[handle_result]
+    pub fn allow_token(&mut self, token_id: AccountId) -> Result<(), HopChainError> {
+        self.assert_owner()?;
+        self.allowed_tokens.insert(&token_id);
+        Ok(())
+    }
 
-use near_sdk::collections::{Vector, LookupMap};
-use near_sdk::{env, Balance, assert_one_yocto};
+    #[handle_result]
+    pub fn disallow_token(&mut self, token_id: AccountId) -> Result<(), HopChainError> {
+        self.assert_owner()?;
+        self.allowed_tokens.remove(&token_id);
+        Ok(())
+    }
 
+    pub fn is_token_allowed(&self, token_id: AccountId) -> bool {
+        self.allowed_tokens.contains(&token_id)
+    }
 
-const MIN_BID: Balance = 10_000_000_000_000_000_000_000;
-const ONE_YOCTO: Balance =1;
-const DEFAULT_FIELD: Vector2 = Vector2{x:7, y:4, id:0};
-#[derive(BorshSerialize, BorshStorageKey)]
-enum StorageKey {
-    Battles,
-    BattleOfPlayer,
-    LastBattleOfPlayer,
-    OpenedBattles
-}
+    #[handle_result]
+    pub fn allow_relayer(&mut self, relayer_id: AccountId) -> Result<(), HopChainError> {
+        self.assert_owner()?;
+        self.relayers.insert(&relayer_id);
+        Ok(())
+    }
 
-// Define the contract structure
-#[near_bindgen]
-#[derive(BorshDeserialize, BorshSerialize)]
-pub struct Contract {
-    battles: Vector<Battle>,
-    battles_of_player: LookupMap<AccountId, i8>,
-    opened_battles: Vector<Battle>,
-    last_player_battle_id: LookupMap<AccountId, i8>,
-}
+    #[handle_result]
+    pub fn disallow_relayer(&mut self, relayer_id: AccountId) -> Result<(), HopChainError> {
+        self.assert_owner()?;
+        self.relayers.remove(&relayer_id);
+        Ok(())
+    }
 
-// Define the default, which automatically initializes the contract
-impl Default for Contract{
-    fn default() -> Self{
-        Self{
-            battles: Vector::new(StorageKey::Battles),
-            battles_of_player: LookupMap::new(StorageKey::BattleOfPlayer),
-            last_player_battle_id: LookupMap::new(StorageKey::LastBattleOfPlayer),
-            opened_battles: Vector::new(StorageKey::OpenedBattles)
+    pub fn is_relayer_allowed(&self, relayer_id: AccountId) -> bool {
+        self.relayers.contains(&relayer_id)
+    }
+
+    #[handle_result]
+    pub fn set_fee(&mut self, fee_basis_points: u16) -> Result<(), HopChainError> {
+        self.assert_owner()?;
+        if fee_basis_points > MAX_FEE_BASIS_POINTS {
+            return Err(HopChainError::InvalidConfig);
         }
+        self.fee_basis_points = fee_basis_points;
+        Ok(())
     }
-}
 
-#[near_bindgen]
-// Implement the contract structure
-impl Contract {
-   
-    #[payable]
-    pub fn join_battle(&mut self, battle_id: i8) -> bool {
-        let (mut battle, battle_index) = self.get_battle(battle_id);
-        assert_eq!(battle.battle_id.clone(), battle_id.clone());
-        assert!(env::attached_deposit() >= battle.bid);
-        battle.join_battle(env::signer_account_id());
-        let op_battle_index = self.opened_battles.iter().position(|x| x.battle_id == battle.battle_id);
-        self.opened_battles.swap_remove(op_battle_index.unwrap().try_into().unwrap());
-        self.battles.push(&battle);
-        self.battles.swap_remove(battle_index.try_into().unwrap());
-        true
+    /// sends collected fees to `receiver`, `token_id` is `None` for NEAR fees.
+    /// Treasury is restored if the transfer fails.
+    #[handle_result]
+    pub fn withdraw_fees(&mut self, amount: U128, receiver: AccountId, token_id: Option<AccountId>) -> Result<Promise, HopChainError> {
+        self.assert_owner()?;
+        let available = self.treasury.get(&token_id).unwrap_or(0);
+        if amount.0 > available {
+            return Err(HopChainError::InsufficientBalance);
+        }
+        self.treasury.insert(&token_id, &(available - amount.0));
+        Ok(self.transfer_pot(receiver, amount.0, token_id.clone())
+            .then(Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_WITHDRAW_CALLBACK)
+                .on_withdraw_fees(amount, token_id)))
+    }
+
+    #[private]
+    pub fn on_withdraw_fees(&mut self, amount: U128, token_id: Option<AccountId>) -> bool {
+        if is_promise_success() {
+            return true;
+        }
+        log!("Withdrawal of {} fees failed, restoring treasury", amount.0);
+        let collected = self.treasury.get(&token_id).unwrap_or(0);
+        self.treasury.insert(&token_id, &(collected + amount.0));
+        false
     }
 
+    pub fn get_treasury(&self, token_id: Option<AccountId>) -> U128 {
+        U128(self.treasury.get(&token_id).unwrap_or(0))
+    }
 
-    pub fn place_units(&mut self, battle_id: i8, units: Vec<(i8,i8,i8)>) {
-        let (mut battle, battle_index) = self.get_battle(battle_id);
-        assert_eq!(battle.battle_id.clone(), battle_id.clone());
-        battle.place_units(units, env::signer_account_id());
-        self.battles.push(&battle);
-        self.battles.swap_remove(battle_index.try_into().unwrap());
+    pub fn get_fee(&self) -> u16 {
+        self.fee_basis_points
     }
 
-    #[private]
-    fn get_battle(&self, battle_id: i8) -> (Battle, usize) {
-        let battles_vec = self.battles.to_vec();
-        let battle_index = battles_vec.iter().position(|b| b.battle_id == battle_id).unwrap();
-        let battle = self.battles.get(battle_index.try_into().unwrap()).unwrap();
-        (battle, battle_index)
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
     }
-    
-    pub fn find_battle(&self) -> (u128,i8){
-        if self.opened_battles.is_empty() {
-            panic!("No opened battles found");
-        }
-        let battle = self.opened_battles.get(0).unwrap();
-        (battle.bid, battle.battle_id)
+
+    #[handle_result]
+    pub fn add_moderator(&mut self, account_id: AccountId) -> Result<(), HopChainError> {
+        self.assert_owner()?;
+        self.moderators.insert(&account_id);
+        Self::emit_event("moderator_added", serde_json::json!({ "account_id": account_id }));
+        Ok(())
     }
 
-    pub fn make_move(&mut self, battle_id: i8, unit_id: i8, moves: Vec<(i8,i8)>) -> bool {
-        let (mut battle, index) = self.get_battle(battle_id);
-        let winner = battle.make_move(unit_id, moves, env::signer_account_id());
-        self.battles.push(&battle);
-        self.battles.swap_remove(index.try_into().unwrap());
-        if winner.is_some() {
-            Promise::new(winner.unwrap()).transfer(battle.bid * 2);
-        }
-        true
+    #[handle_result]
+    pub fn remove_moderator(&mut self, account_id: AccountId) -> Result<(), HopChainError> {
+        self.assert_owner()?;
+        self.moderators.remove(&account_id);
+        Self::emit_event("moderator_removed", serde_json::json!({ "account_id": account_id }));
+        Ok(())
     }
 
-    #[payable]
-    pub fn create_battle(&mut self) -> i8 {
-        assert!(near_sdk::env::attached_deposit() >= MIN_BID, "Attached deposit is less than minimum bid");
-        let battle_id = self.battles.len() as i8;
-        let battle = Battle::new(
-            near_sdk::env::attached_deposit(),
-            env::signer_account_id(),
-            battle_id,
-            DEFAULT_FIELD.x,
-            DEFAULT_FIELD.y);
-        self.battles.push(&battle);
-        self.last_player_battle_id.insert(&env::signer_account_id(), &battle_id.clone());
-        self.opened_battles.push(&battle);
-        battle_id
+    pub fn is_moderator(&self, account_id: AccountId) -> bool {
+        self.moderators.contains(&account_id)
     }
 
-    pub fn get_battle_info(&self, battle_id: i8) -> Battle {
-        self.get_battle(battle_id).0
+    /// Owner or any moderator can pause the game, only owner can unpause it.
+    /// Players get a full move timeout again after the contract is unpaused.
+    #[handle_result]
+    pub fn pause(&mut self) -> Result<(), HopChainError> {
+        self.assert_owner_or_moderator()?;
+        self.paused = true;
+        Self::emit_event("paused", serde_json::json!({ "by": env::predecessor_account_id() }));
+        Ok(())
     }
 
-    pub fn get_last_battle_of_player (&self, player_account: AccountId) -> Option<i8> {
-        
-        self.last_player_battle_id.get(&player_account)
+    #[handle_result]
+    pub fn unpause(&mut self) -> Result<(), HopChainError> {
+        self.assert_owner()?;
+        self.paused = false;
+        self.resumed_at = env::block_timestamp();
+        Self::emit_event("unpaused", serde_json::json!({ "by": env::predecessor_account_id() }));
+        Ok(())
     }
 
-    pub fn leave_battle (&mut self) {
-        self.last_player_battle_id.remove(&env::signer_account_id());
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// new board size only applies to battles created after the change
+    #[handle_result]
+    pub fn set_config(&mut self, config: Config) -> Result<(), HopChainError> {
+        self.assert_owner()?;
+        config.validate()?;
+        self.config = config;
+        Self::emit_event("config_changed", serde_json::json!({ "config": self.config }));
+        Ok(())
     }
-}
\ No newline at end of file
+
+    pub fn get_config(&self) -> Config {
+        self.config.clone()
+    }
+
+    /// Ends a battle that is stuck or disputed. Only owner or moderators can call it,
+    /// pot is paid out like in a normal win, `Draw` returns both bids.
+    /// Storage it uses is paid from the attached deposit or the caller's balance.
+    #[payable]
+    #[handle_result]
+    pub fn resolve_battle(&mut self, battle_id: u32, outcome: BattleOutcome) -> Result<(), HopChainError> {
+        self.assert_owner_or_moderator()?;
+        let initial_storage = env::storage_usage();
+        let mut battle = self.get_battle(battle_id)?;
+        if battle.game.current_state == 3 || battle.game.current_state == 4 {
+            return Err(HopChainError::WrongState);
+        }
+        // a battle nobody has joined is cancelled by its creator instead
+        let (first_player, second_player) = match (battle.game.first_player.clone(), battle.game.second_player.clone()) {
+            (Some(first_player), Some(second_player)) => (first_player, second_player),
+            _ => return Err(HopChainError::WrongState)
+        };
+        battle.game.winner = match outcome {
+            BattleOutcome::FirstPlayerWins => Some(first_player.clone()),
+            BattleOutcome::SecondPlayerWins => Some(second_player.clone()),
+            BattleOutcome::Draw => None
+        };
+        battle.game.current_state = 3;
+        battle.ended_at = Some(env::block_timestamp());
+        self.refund_double_offer(&mut battle);
+        self.save_battle(&battle);
+        self.finish_battle(battle_id);
+        match &battle.game.winner {
+            Some(winner) => {
+                self.pay_out_pot(winner.clone(), battle.bid * 2, battle.token.clone());
+            },
+            None => {
+                self.internal_deposit(&battle.payer_of(&first_player), &battle.token, battle.bid);
+                self.internal_deposit(&battle.payer_of(&second_player), &battle.token, battle.bid);
+            }
+        }
+        self.settle_storage(&env::predecessor_account_id(), initial_storage, env::attached_deposit())?;
+        Self::emit_event("battle_resolved", serde_json::json!({
+            "battle_id": battle_id,
+            "outcome": outcome,
+            "winner": battle.game.winner,
+            "by": env::predecessor_account_id()
+        }));
+        Ok(())
+    }
+
+    /// Lets the player who is waiting win the battle when the opponent has not moved
+    /// for longer than `move_timeout` from the config, counted from the last unpause at the latest.
+    /// Players can't move while the contract is paused, so timeouts can't be claimed either. Storage it uses is paid from the attached deposit
+    /// or the caller's balance. Fails with `WrongState` when move timeouts are disabled.
+    #[payable]
+    #[handle_result]
+    pub fn claim_timeout(&mut self, battle_id: u32) -> Result<(), HopChainError> {
+        self.assert_not_paused()?;
+        if self.config.move_timeout.0 == 0 {
+            return Err(HopChainError::WrongState);
+        }
+        let initial_storage = env::storage_usage();
+        let mut battle = self.get_battle(battle_id)?;
+        if battle.game.current_state != 2 {
+            return Err(HopChainError::WrongState);
+        }
+        // time spent paused doesn't count, nobody could move then
+        if env::block_timestamp() - battle.last_action_at.max(self.resumed_at) <= self.config.move_timeout.0 {
+            return Err(HopChainError::TimeoutNotReached);
+        }
+        let caller = env::predecessor_account_id();
+        // a double nobody answered in time counts as declined
+        let waiting_player = match &battle.double_offered_by {
+            Some(offerer) => Some(offerer.clone()),
+            None if battle.game.current_move_owner == 0 => battle.game.second_player.clone(),
+            None => battle.game.first_player.clone()
+        };
+        if waiting_player.as_ref() != Some(&caller) {
+            return Err(HopChainError::Unauthorized);
+        }
+        battle.game.winner = Some(caller.clone());
+        battle.game.current_state = 3;
+        battle.ended_at = Some(env::block_timestamp());
+        self.refund_double_offer(&mut battle);
+        self.save_battle(&battle);
+        self.finish_battle(battle_id);
+        self.pay_out_pot(caller.clone(), battle.bid * 2, battle.token.clone());
+        self.settle_battle_storage(battle_id, &caller, initial_storage, env::attached_deposit())?;
+        Self::emit_event("timeout_claimed", serde_json::json!({ "battle_id": battle_id, "winner": battle.game.winner }));
+        Ok(())
+    }
+
+    /// Allows `delegate` to place units and make moves for the caller in `battle_id`, or in all
+    /// battles if it is `None`, until `expires_at` or until revoked. Replaces previous delegation
+    /// of the same account. Storage is paid from the attached deposit or the caller's balance.
+    #[payable]
+    #[handle_result]
+    pub fn authorize_delegate(&mut self, delegate: AccountId, battle_id: Option<u32>, expires_at: Option<U64>) -> Result<(), HopChainError> {
+        let player = env::predecessor_account_id();
+        if player == delegate {
+            return Err(HopChainError::InvalidDelegation);
+        }
+        let initial_storage = env::storage_usage();
+        self.delegations.insert(&(player.clone(), delegate), &Delegation { battle_id, expires_at });
+        self.settle_storage(&player, initial_storage, env::attached_deposit())?;
+        Ok(())
+    }
+
+    #[handle_result]
+    pub fn revoke_delegate(&mut self, delegate: AccountId) -> Result<(), HopChainError> {
+        let player = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        if self.delegations.remove(&(player.clone(), delegate)).is_none() {
+            return Err(HopChainError::InvalidDelegation);
+        }
+        self.settle_storage(&player, initial_storage, 0)?;
+        Ok(())
+    }
+
+    pub fn get_delegation(&self, player: AccountId, delegate: AccountId) -> Option<Delegation> {
+        self.delegations.get(&(player, delegate))
+    }
+
+    /// Adds `public_key` as a function call access key of this contract limited to `make_move`
+    /// and `place_units`, moves signed with it are made for the caller in `battle_id` until
+    /// `expires_at`. Key is deleted when the battle ends. Attached deposit must cover the key's
+    /// gas allowance and storage, the rest is credited to the caller's balance.
+    /// The session is recorded by `on_session_key_added` once the key is added.
+    #[payable]
+    #[handle_result]
+    pub fn authorize_session(&mut self, public_key: PublicKey, battle_id: u32, expires_at: U64) -> Result<Promise, HopChainError> {
+        let player = env::predecessor_account_id();
+        let battle = self.get_battle(battle_id)?;
+        if battle.game.first_player.as_ref() != Some(&player) && battle.game.second_player.as_ref() != Some(&player) {
+            return Err(HopChainError::NotYourBattle);
+        }
+        if battle.game.current_state == 3 || battle.game.current_state == 4 {
+            return Err(HopChainError::WrongState);
+        }
+        if expires_at.0 <= env::block_timestamp() || self.sessions.contains_key(&public_key) {
+            return Err(HopChainError::InvalidSession);
+        }
+        let deposit = env::attached_deposit();
+        if deposit < SESSION_KEY_ALLOWANCE {
+            return Err(HopChainError::InsufficientDeposit(SESSION_KEY_ALLOWANCE));
+        }
+        let session = Session { player, battle_id, expires_at };
+        Ok(Promise::new(env::current_account_id())
+            .add_access_key(public_key.clone(), SESSION_KEY_ALLOWANCE, env::current_account_id(), SESSION_KEY_METHODS.to_string())
+            .then(Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_SESSION_CALLBACK)
+                .on_session_key_added(public_key, session, U128(deposit))))
+    }
+
+    /// Records the session once its key is added, storage is paid by the player from `deposit`.
+    /// If the key couldn't be added, e.g. because the account already has it, the battle has ended
+    /// meanwhile or `deposit` doesn't cover the storage, all of `deposit` goes back to the player's balance.
+    #[private]
+    pub fn on_session_key_added(&mut self, public_key: PublicKey, session: Session, deposit: U128) -> bool {
+        let added = is_promise_success();
+        if added && self.record_session(&public_key, &session, deposit.0).is_ok() {
+            return true;
+        }
+        // only keys this contract added and nobody else uses are deleted
+        if added && !self.sessions.contains_key(&public_key) {
+            Promise::new(env::current_account_id()).delete_key(public_key);
+        }
+        self.deposit_storage_refund(&session.player, deposit.0);
+        false
+    }
+
+    /// Deletes a session key before its battle ends, can be called by its player.
+    /// Storage of the session goes back to the player's balance, the gas allowance doesn't.
+    #[handle_result]
+    pub fn revoke_session(&mut self, public_key: PublicKey) -> Result<Promise, HopChainError> {
+        let session = self.sessions.get(&public_key).ok_or(HopChainError::InvalidSession)?;
+        if session.player != env::predecessor_account_id() {
+            return Err(HopChainError::Unauthorized);
+        }
+        // keys of ended battles are already deleted, their sessions are cleared by `prune`
+        if self.get_battle(session.battle_id)?.game.current_state >= 3 {
+            return Err(HopChainError::WrongState);
+        }
+        self.remove_session(&public_key);
+        Ok(Promise::new(env::current_account_id()).delete_key(public_key))
+    }
+
+    pub fn get_session(&self, public_key: PublicKey) -> Option<Session> {
+        self.sessions.get(&public_key)
+    }
+
+    #[handle_result]
+    pub fn get_battle_info(&self, battle_id: u32) -> Result<BattleView, HopChainError> {
+        Ok(BattleView::from(&self.get_battle(battle_id)?))
+    }
+
+    /// living units of the battle ordered by unit id
+    #[handle_result]
+    pub fn get_units(&self, battle_id: u32) -> Result<Vec<UnitView>, HopChainError> {
+        Ok(self.get_battle(battle_id)?.game.units.values().map(UnitView::from).collect())
+    }
+
+    /// history of the battle in the text notation, archived battles have no history
+    #[handle_result]
+    pub fn get_battle_notation(&self, battle_id: u32) -> Result<String, HopChainError> {
+        let battle = self.get_battle(battle_id)?;
+        let mut notation = Notation::of_game(&battle.game);
+        notation.tags.insert(0, ("Battle".to_string(), battle_id.to_string()));
+        Ok(notation.to_string())
+    }
+
+    pub fn get_battle_summary(&self, battle_id: u32) -> Option<BattleSummary> {
+        self.archived_battles.get(&battle_id)
+    }
+
+    /// Archives up to `limit` finished battles: the full battle is replaced by a `BattleSummary`
+    /// and the storage still paid for by the players is credited back to whoever paid for it.
+    /// Sessions of the battle are cleared and refunded to their players. Anyone can call it.
+    /// Returns number of archived battles.
+    #[handle_result]
+    pub fn prune(&mut self, limit: u32) -> Result<u32, HopChainError> {
+        let mut archived = 0;
+        while archived < limit {
+            let battle_id = match self.finished_battles.pop() {
+                Some(battle_id) => battle_id,
+                None => break
+            };
+            for key in self.battle_sessions.get(&battle_id).unwrap_or_default() {
+                self.remove_session(&key);
+            }
+            let initial_storage = env::storage_usage();
+            let mut battle = self.get_battle(battle_id)?;
+            self.archived_battles.insert(&battle_id, &BattleSummary::from(&battle));
+            self.battles.remove(&battle_id);
+            self.release_battle_storage(&mut battle, initial_storage.saturating_sub(env::storage_usage()), None);
+            archived += 1;
+        }
+        Ok(archived)
+    }
+
+    pub fn get_last_battle_of_player (&self, player_account: AccountId) -> Option<u32> {
+        
+        self.last_player_battle_id.get(&player_account)
+    }
+
+    pub fn leave_battle (&mut self) {
+        self.last_player_battle_id.remove(&env::predecessor_account_id());
+    }
+}
+
+impl Contract {
+    fn internal_create_battle(&mut self, creator: AccountId, bid: Balance, token: Option<AccountId>, sponsor: Option<AccountId>) -> Result<u32, HopChainError> {
+        let battle_id = self.next_battle_id;
+        let mut battle = Battle::new(
+            bid,
+            token,
+            creator.clone(),
+            battle_id,
+            self.config.board_width,
+            self.config.board_height)?;
+        self.next_battle_id += 1;
+        battle.first_player_sponsor = sponsor;
+        self.save_battle(&battle);
+        self.last_player_battle_id.insert(&creator, &battle_id.clone());
+        self.opened_battles.push(&battle_id);
+        Ok(battle_id)
+    }
+
+    /// returns the bid of the joined battle, `deposit` must cover it and be in the battle's token
+    fn internal_join_battle(&mut self, battle_id: u32, player: AccountId, deposit: Balance, token: Option<AccountId>, sponsor: Option<AccountId>) -> Result<Balance, HopChainError> {
+        let mut battle = self.get_battle(battle_id)?;
+        if battle.token != token {
+            return Err(HopChainError::WrongToken);
+        }
+        if deposit < battle.bid {
+            return Err(HopChainError::InsufficientDeposit(battle.bid));
+        }
+        // spectators who bet on the battle can't play in it
+        if self.bets.contains_key(&(battle_id, player.clone())) || sponsor.iter().any(|s| self.bets.contains_key(&(battle_id, s.clone()))) {
+            return Err(HopChainError::Unauthorized);
+        }
+        battle.game.join(player)?;
+        battle.second_player_sponsor = sponsor;
+        battle.last_action_at = env::block_timestamp();
+        if let Some(op_battle_index) = self.opened_battles.iter().position(|x| x == battle.battle_id) {
+            self.opened_battles.swap_remove(op_battle_index as u64);
+        }
+        self.save_battle(&battle);
+        Ok(battle.bid)
+    }
+
+    fn migrate_from_v1(owner_id: AccountId) -> Self {
+        let old: ContractV1 = env::state_read().expect("No state to migrate");
+        let mut old_battles = old.battles;
+        let battles = old_battles.to_vec();
+        // battles are keyed by id now, the old list is removed before they are written back under its prefix
+        old_battles.clear();
+        let opened_battles: Vec<u32> = old.opened_battles.iter().map(|b| b.battle_id as u32).collect();
+        let mut contract = Self::new(owner_id, 0);
+        // other collections keep their storage prefixes, so old elements are overwritten in place
+        for battle in battles {
+            let battle = Battle::from(battle);
+            if battle.game.current_state == 3 {
+                contract.finished_battles.push(&battle.battle_id);
+            }
+            contract.next_battle_id = contract.next_battle_id.max(battle.battle_id + 1);
+            contract.save_battle(&battle);
+        }
+        for battle_id in opened_battles {
+            contract.opened_battles.push(&battle_id);
+        }
+        contract
+    }
+
+    /// Reply of the contract in a practice battle, the greedy move over legal hop chains.
+    /// Returns the outcome of the reply. A bot without a legal move resigns, so the battle
+    /// doesn't wait for it forever: `outcome` of the player's move then ends the battle.
+    fn play_bot_move(battle: &mut Battle, bot: &AccountId, outcome: &mut MoveOutcome) -> Option<MoveOutcome> {
+        let reply = battle.game.greedy_move(bot, BOT_MAX_SCORED_MOVES)
+            .and_then(|(unit_id, moves)| battle.game.make_move(unit_id, moves, bot.clone()).ok());
+        match reply {
+            Some(reply) => {
+                Self::emit_event("bot_moved", serde_json::json!({ "battle_id": battle.battle_id, "outcome": reply }));
+                Some(reply)
+            },
+            None => {
+                battle.game.winner = battle.game.first_player.clone();
+                battle.game.current_state = 3;
+                outcome.next_move_owner = None;
+                outcome.battle_ended = true;
+                outcome.winner = battle.game.winner.clone();
+                Self::emit_event("bot_resigned", serde_json::json!({ "battle_id": battle.battle_id, "winner": outcome.winner }));
+                None
+            }
+        }
+    }
+
+    /// Queues the battle for archiving and deletes its session keys.
+    /// Sessions stay recorded until `prune`, which refunds them.
+    fn finish_battle(&mut self, battle_id: u32) {
+        self.finished_battles.push(&battle_id);
+        for key in self.battle_sessions.get(&battle_id).unwrap_or_default() {
+            Promise::new(env::current_account_id()).delete_key(key);
+        }
+    }
+
+    /// stores a session whose key was added, storage is paid by the player
+    fn record_session(&mut self, public_key: &PublicKey, session: &Session, deposit: Balance) -> Result<(), HopChainError> {
+        if self.get_battle(session.battle_id)?.game.current_state >= 3 || self.sessions.contains_key(public_key) {
+            return Err(HopChainError::WrongState);
+        }
+        let initial_storage = env::storage_usage();
+        self.sessions.insert(public_key, session);
+        let mut keys = self.battle_sessions.get(&session.battle_id).unwrap_or_default();
+        keys.push(public_key.clone());
+        self.battle_sessions.insert(&session.battle_id, &keys);
+        if let Err(error) = self.settle_storage(&session.player, initial_storage, deposit - SESSION_KEY_ALLOWANCE) {
+            self.sessions.remove(public_key);
+            keys.pop();
+            self.battle_sessions.insert(&session.battle_id, &keys);
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Forgets the session of `public_key`, its storage is credited to the player.
+    /// The key itself must be deleted by the caller.
+    fn remove_session(&mut self, public_key: &PublicKey) {
+        let session = match self.sessions.get(public_key) {
+            Some(session) => session,
+            None => return
+        };
+        let initial_storage = env::storage_usage();
+        let mut keys = self.battle_sessions.get(&session.battle_id).unwrap_or_default();
+        keys.retain(|key| key != public_key);
+        if keys.is_empty() {
+            self.battle_sessions.remove(&session.battle_id);
+        }
+        else {
+            self.battle_sessions.insert(&session.battle_id, &keys);
+        }
+        self.sessions.remove(public_key);
+        let freed = initial_storage.saturating_sub(env::storage_usage()) as Balance * env::storage_byte_cost();
+        self.deposit_storage_refund(&session.player, freed);
+    }
+
+    fn assert_owner(&self) -> Result<(), HopChainError> {
+        if env::predecessor_account_id() != self.owner_id {
+            return Err(HopChainError::Unauthorized);
+        }
+        Ok(())
+    }
+
+    fn assert_owner_or_moderator(&self) -> Result<(), HopChainError> {
+        let caller = env::predecessor_account_id();
+        if caller != self.owner_id && !self.moderators.contains(&caller) {
+            return Err(HopChainError::Unauthorized);
+        }
+        Ok(())
+    }
+
+    fn assert_not_paused(&self) -> Result<(), HopChainError> {
+        if self.paused {
+            return Err(HopChainError::Paused);
+        }
+        Ok(())
+    }
+
+    fn assert_bid_in_limits(&self, bid: Balance) -> Result<(), HopChainError> {
+        if bid < self.config.min_bid.0 || self.config.max_bid.is_some_and(|max_bid| bid > max_bid.0) {
+            return Err(HopChainError::BidOutOfLimits);
+        }
+        Ok(())
+    }
+
+    /// logs a NEP-297 event so indexers can follow admin actions
+    fn emit_event(event: &str, data: serde_json::Value) {
+        log!("EVENT_JSON:{}", serde_json::json!({
+            "standard": EVENT_STANDARD,
+            "version": EVENT_VERSION,
+            "event": event,
+            "data": [data]
+        }));
+    }
+
+    /// Takes the platform fee from the pot and credits the rest to the winner's balance, returns the credited amount.
+    /// Ledger rows it creates are paid by the caller, so it runs before storage is settled.
+    fn pay_out_pot(&mut self, winner: AccountId, pot: Balance, token: Option<AccountId>) -> Balance {
+        let fee = self.collect_fee(pot, &token);
+        self.internal_deposit(&winner, &token, pot - fee);
+        pot - fee
+    }
+
+    /// moves the platform fee on `amount` to the treasury and returns it
+    fn collect_fee(&mut self, amount: Balance, token: &Option<AccountId>) -> Balance {
+        let fee = amount * self.fee_basis_points as u128 / MAX_FEE_BASIS_POINTS as u128;
+        if fee > 0 {
+            let collected = self.treasury.get(token).unwrap_or(0);
+            self.treasury.insert(token, &(collected + fee));
+        }
+        fee
+    }
+
+    /// Player the call is made for: the caller itself, or `on_behalf_of` when the caller
+    /// is a delegate of that player allowed to play in `battle_id`.
+    /// Transactions signed with a session key come from this contract itself,
+    /// then the player is the owner of the session, other calls of the contract itself are rejected.
+    fn resolve_player(&self, battle_id: u32, on_behalf_of: Option<AccountId>) -> Result<AccountId, HopChainError> {
+        let caller = env::predecessor_account_id();
+        if caller == env::current_account_id() {
+            let public_key = env::signer_account_pk();
+            if let Some(session) = self.sessions.get(&public_key) {
+                if session.battle_id != battle_id || env::block_timestamp() >= session.expires_at.0 {
+                    return Err(HopChainError::InvalidSession);
+                }
+                return Ok(session.player);
+            }
+            // the contract plays only as the practice bot and replies inside the player's `make_move`
+            return Err(HopChainError::Unauthorized);
+        }
+        match on_behalf_of {
+            Some(player) if player != caller => {
+                let delegation = self.delegations.get(&(player.clone(), caller)).ok_or(HopChainError::InvalidDelegation)?;
+                if !delegation.allows(battle_id) {
+                    return Err(HopChainError::InvalidDelegation);
+                }
+                Ok(player)
+            },
+            _ => Ok(caller)
+        }
+    }
+
+    /// Player and sponsor of a free play call: the player signed the delegate action, the relayer
+    /// sponsors it. Only relayers allowed by the owner sponsor, else any contract a user calls
+    /// could spend the user's balance. Deposit attached by the player is credited to the player's balance.
+    fn sponsored_player(&mut self) -> Result<(AccountId, AccountId), HopChainError> {
+        let player = env::predecessor_account_id();
+        let sponsor = env::signer_account_id();
+        if player == sponsor || !self.relayers.contains(&sponsor) {
+            return Err(HopChainError::Unauthorized);
+        }
+        self.deposit_storage_refund(&player, env::attached_deposit());
+        Ok((player, sponsor))
+    }
+
+    /// takes `amount` of NEAR from the balance of `account_id`
+    fn take_balance(&mut self, account_id: &AccountId, amount: Balance) -> Result<(), HopChainError> {
+        let key = (account_id.clone(), None);
+        let balance = self.balances.get(&key).unwrap_or(0);
+        if balance < amount {
+            return Err(HopChainError::InsufficientBalance);
+        }
+        self.balances.insert(&key, &(balance - amount));
+        Ok(())
+    }
+
+    /// Attached deposit that pays storage of `player`. A delegate playing for the player gets
+    /// its deposit back on its own balance, storage is paid from the player's balance then.
+    fn deposit_of(&mut self, player: &AccountId) -> Balance {
+        let caller = env::predecessor_account_id();
+        if &caller == player {
+            return env::attached_deposit();
+        }
+        self.deposit_storage_refund(&caller, env::attached_deposit());
+        0
+    }
+
+    /// Charges storage used since `initial_storage` for `player` and records it on the battle,
+    /// storage is paid by the player's sponsor in free play. Storage freed since then
+    /// is credited back to whoever paid for the storage of `player`, see `release_battle_storage`.
+    fn settle_battle_storage(&mut self, battle_id: u32, player: &AccountId, initial_storage: StorageUsage, deposit: Balance) -> Result<(), HopChainError> {
+        self.settle_battle_storage_freed_by(battle_id, player, &[], initial_storage, deposit)
+    }
+
+    /// like `settle_battle_storage`, storage freed since `initial_storage` was taken up by `owners` in equal parts,
+    /// e.g. one entry for every unit killed by `player`. Freed storage goes to `player` when `owners` is empty.
+    fn settle_battle_storage_freed_by(&mut self, battle_id: u32, player: &AccountId, owners: &[AccountId], initial_storage: StorageUsage, deposit: Balance) -> Result<(), HopChainError> {
+        let mut battle = self.get_battle(battle_id)?;
+        let payer = battle.payer_of(player);
+        let freed = initial_storage.saturating_sub(env::storage_usage());
+        if freed == 0 {
+            let bytes = self.settle_storage(&payer, initial_storage, deposit)?;
+            battle.add_storage(player, bytes as StorageUsage);
+        }
+        else {
+            self.deposit_storage_refund(&payer, deposit);
+            let owners = if owners.is_empty() { std::slice::from_ref(player) } else { owners };
+            let share = freed / owners.len() as StorageUsage;
+            for (i, owner) in owners.iter().enumerate() {
+                // the last owner also gets the bytes left over by the division
+                let bytes = if i + 1 == owners.len() { freed - share * i as StorageUsage } else { share };
+                self.release_battle_storage(&mut battle, bytes, Some(owner));
+            }
+        }
+        self.save_battle(&battle);
+        Ok(())
+    }
+
+    /// Charges storage used since `initial_storage` to `account_id`, paid from `deposit` first and
+    /// from the account's NEAR balance after that. Freed storage and unused deposit are credited
+    /// to the account's balance. Returns the change of storage in bytes.
+    fn settle_storage(&mut self, account_id: &AccountId, initial_storage: StorageUsage, deposit: Balance) -> Result<i64, HopChainError> {
+        let current_storage = env::storage_usage();
+        let byte_cost = env::storage_byte_cost();
+        let bytes = current_storage as i64 - initial_storage as i64;
+        if bytes > 0 {
+            let cost = bytes as Balance * byte_cost;
+            if deposit >= cost {
+                self.deposit_storage_refund(account_id, deposit - cost);
+            }
+            else {
+                let key = (account_id.clone(), None);
+                let balance = self.balances.get(&key).unwrap_or(0);
+                if deposit + balance < cost {
+                    return Err(HopChainError::InsufficientDeposit(cost - balance));
+                }
+                self.balances.insert(&key, &(balance + deposit - cost));
+            }
+        }
+        else {
+            self.deposit_storage_refund(account_id, deposit + (-bytes) as Balance * byte_cost);
+        }
+        Ok(bytes)
+    }
+
+    /// Credits NEAR left over after storage was settled. A new balance row is paid from
+    /// the amount itself, amounts that don't cover their own row are sent back instead.
+    fn deposit_storage_refund(&mut self, account_id: &AccountId, amount: Balance) {
+        let key = (account_id.clone(), None);
+        if amount == 0 || self.balances.contains_key(&key) {
+            self.internal_deposit(account_id, &None, amount);
+            return;
+        }
+        let initial_storage = env::storage_usage();
+        self.balances.insert(&key, &amount);
+        let cost = (env::storage_usage() - initial_storage) as Balance * env::storage_byte_cost();
+        if amount > cost {
+            self.balances.insert(&key, &(amount - cost));
+        }
+        else {
+            self.balances.remove(&key);
+            Promise::new(account_id.clone()).transfer(amount);
+        }
+    }
+
+    /// Refunds `bytes` of freed storage to whoever paid for the storage of `owner` first, the rest to the other player.
+    /// Without `owner` the payer of a tournament battle's creation is refunded first, the rest is split
+    /// between players by the storage they still have paid for. Never refunds more than that, refunded bytes
+    /// are taken off `creation_storage`, `first_player_storage` and `second_player_storage`.
+    fn release_battle_storage(&mut self, battle: &mut Battle, bytes: StorageUsage, owner: Option<&AccountId>) {
+        let byte_cost = env::storage_byte_cost();
+        let mut bytes = bytes;
+        if let (None, Some(payer)) = (owner, battle.creation_payer.clone()) {
+            let released = bytes.min(battle.creation_storage);
+            battle.creation_storage -= released;
+            bytes -= released;
+            self.deposit_storage_refund(&payer, released as Balance * byte_cost);
+        }
+        let paid = battle.first_player_storage + battle.second_player_storage;
+        if paid == 0 || bytes == 0 {
+            return;
+        }
+        let released = bytes.min(paid);
+        let first_player_released = if owner.is_some() && owner == battle.game.first_player.as_ref() {
+            released.min(battle.first_player_storage)
+        }
+        else if owner.is_some() && owner == battle.game.second_player.as_ref() {
+            released - released.min(battle.second_player_storage)
+        }
+        else {
+            (released as u128 * battle.first_player_storage as u128 / paid as u128) as StorageUsage
+        };
+        let second_player_released = released - first_player_released;
+        battle.first_player_storage -= first_player_released;
+        battle.second_player_storage -= second_player_released;
+        if let Some(first_player) = battle.game.first_player.clone() {
+            self.deposit_storage_refund(&battle.payer_of(&first_player), first_player_released as Balance * byte_cost);
+        }
+        if let Some(second_player) = battle.game.second_player.clone() {
+            self.deposit_storage_refund(&battle.payer_of(&second_player), second_player_released as Balance * byte_cost);
+        }
+    }
+
+    fn internal_deposit(&mut self, account_id: &AccountId, token: &Option<AccountId>, amount: Balance) {
+        if amount == 0 {
+            return;
+        }
+        let key = (account_id.clone(), token.clone());
+        let balance = self.balances.get(&key).unwrap_or(0);
+        self.balances.insert(&key, &(balance + amount));
+    }
+
+    fn transfer_pot(&self, receiver: AccountId, amount: Balance, token: Option<AccountId>) -> Promise {
+        match token {
+            Some(token_id) => ext_ft::ext(token_id)
+                .with_attached_deposit(ONE_YOCTO)
+                .with_static_gas(GAS_FOR_FT_TRANSFER)
+                .ft_transfer(receiver, U128(amount), None),
+            None => Promise::new(receiver).transfer(amount)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::{testing_env, PromiseResult, ONE_NEAR};
+
+    pub(crate) fn account(name: &str) -> AccountId {
+        name.parse().unwrap()
+    }
+
+    /// Context of a NEP-366 delegate action: relayer signs and pays for the transaction,
+    /// the contract is called by the user who signed the delegate action.
+    pub(crate) fn relayed_call(user: &str, relayer: &str) -> VMContextBuilder {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id(account("hopchain.near"))
+            .signer_account_id(account(relayer))
+            .predecessor_account_id(account(user))
+            .attached_deposit(0);
+        context
+    }
+
+    pub(crate) fn call(caller: &str, deposit: Balance) -> VMContextBuilder {
+        let mut context = VMContextBuilder::new();
+        context
+            .current_account_id(account("hopchain.near"))
+            .signer_account_id(account(caller))
+            .predecessor_account_id(account(caller))
+            .attached_deposit(deposit);
+        context
+    }
+
+    /// `ft_on_transfer` called by `token`, returns the tokens the contract gives back to the sender
+    pub(crate) fn ft_transfer_call(contract: &mut Contract, token: &str, sender: &str, amount: u128, msg: &str) -> Result<u128, HopChainError> {
+        testing_env!(call(token, 0).build());
+        match contract.ft_on_transfer(account(sender), U128(amount), msg.to_string()) {
+            Ok(PromiseOrValue::Value(unused)) => Ok(unused.0),
+            Ok(_) => unreachable!(),
+            Err(error) => Err(error)
+        }
+    }
+
+    pub(crate) fn sponsored_battle(contract: &mut Contract) -> u32 {
+        testing_env!(call("owner.near", 0).build());
+        contract.allow_relayer(account("relayer.near")).unwrap();
+        testing_env!(call("relayer.near", 2 * ONE_NEAR).build());
+        contract.deposit();
+        testing_env!(relayed_call("alice.near", "relayer.near").build());
+        let battle_id = contract.create_sponsored_battle(U128(DEFAULT_MIN_BID)).unwrap();
+        testing_env!(relayed_call("bob.near", "relayer.near").build());
+        contract.join_sponsored_battle(battle_id).unwrap();
+        battle_id
+    }
+
+    #[test]
+    fn relayed_players_are_identified_by_predecessor() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        let battle_id = sponsored_battle(&mut contract);
+
+        testing_env!(relayed_call("alice.near", "relayer.near").build());
+        contract.place_units(battle_id, vec![(2, 1, 0), (1, 2, 0)], None).unwrap();
+        testing_env!(relayed_call("bob.near", "relayer.near").build());
+        contract.place_units(battle_id, vec![(0, 4, 0)], None).unwrap();
+
+        let battle = contract.get_battle(battle_id).unwrap();
+        assert_eq!(battle.game.first_player_units, vec![0, 1]);
+        assert_eq!(battle.game.second_player_units, vec![2]);
+        assert_eq!(battle.game.current_state, 2);
+        assert!(contract.get_balance(account("relayer.near"), None).0 > 0);
+        assert_eq!(contract.get_balance(account("alice.near"), None).0, 0);
+        assert_eq!(contract.get_balance(account("bob.near"), None).0, 0);
+    }
+
+    #[test]
+    fn relayed_winner_gets_the_sponsored_pot() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        let battle_id = sponsored_battle(&mut contract);
+        testing_env!(relayed_call("alice.near", "relayer.near").build());
+        contract.place_units(battle_id, vec![(2, 1, 0), (1, 2, 0)], None).unwrap();
+        testing_env!(relayed_call("bob.near", "relayer.near").build());
+        contract.place_units(battle_id, vec![(0, 4, 0)], None).unwrap();
+
+        // skeleton hops over the orc and hits the mage for 1.5x damage
+        testing_env!(relayed_call("alice.near", "relayer.near").build());
+        contract.make_move(battle_id, 0, vec![(1, 0), (3, 0)], None).unwrap();
+
+        let battle = contract.get_battle(battle_id).unwrap();
+        assert_eq!(battle.game.winner, Some(account("alice.near")));
+        assert_eq!(contract.get_balance(account("alice.near"), None).0, 2 * DEFAULT_MIN_BID);
+        assert_eq!(contract.get_balance(account("bob.near"), None).0, 0);
+    }
+
+    #[test]
+    fn make_move_reports_outcome() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        let battle_id = sponsored_battle(&mut contract);
+        testing_env!(call("alice.near", 0).build());
+        contract.place_units(battle_id, vec![(2, 1, 0), (1, 2, 0)], None).unwrap();
+        testing_env!(call("bob.near", 0).build());
+        contract.place_units(battle_id, vec![(0, 4, 0), (1, 5, 0)], None).unwrap();
+
+        testing_env!(call("alice.near", 0).build());
+        let played = contract.make_move(battle_id, 0, vec![(1, 0), (3, 0)], None).unwrap();
+        assert!(played.bot_reply.is_none());
+        let outcome = played.outcome;
+
+        assert_eq!(outcome.final_position, (3, 0));
+        assert_eq!(outcome.damage_multiplier, 1.5);
+        assert_eq!(outcome.damage, 15.0);
+        assert_eq!(outcome.damaged_units.len(), 2);
+        let mage = outcome.damaged_units.iter().find(|u| u.unit_id == 2).unwrap();
+        assert_eq!((mage.hp_before, mage.hp_after), (12.0, 0.0));
+        let orc = outcome.damaged_units.iter().find(|u| u.unit_id == 1).unwrap();
+        assert_eq!((orc.hp_before, orc.hp_after), (40.0, 25.0));
+        assert_eq!(outcome.killed_units, vec![2]);
+        assert_eq!(outcome.next_move_owner, Some(account("bob.near")));
+        assert!(!outcome.battle_ended);
+        assert_eq!(outcome.winner, None);
+    }
+
+    #[test]
+    fn battle_history_is_exported_in_notation() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        let battle_id = sponsored_battle(&mut contract);
+        testing_env!(call("alice.near", 0).build());
+        contract.place_units(battle_id, vec![(2, 1, 0), (1, 2, 0)], None).unwrap();
+        testing_env!(call("bob.near", 0).build());
+        contract.place_units(battle_id, vec![(0, 4, 0), (1, 5, 0)], None).unwrap();
+        testing_env!(call("alice.near", 0).build());
+        contract.make_move(battle_id, 0, vec![(1, 0), (3, 0)], None).unwrap();
+
+        let text = contract.get_battle_notation(battle_id).unwrap();
+        assert_eq!(text, format!("[Battle \"{}\"]\n[Desk \"7x4\"]\n[First \"alice.near\"]\n[Second \"bob.near\"]\n[Result \"*\"]\n\n\
+            S@b1 O@c1\nm@e1 o@f1\n1. S b1-d1\n", battle_id));
+        let replayed = Notation::parse(&text).unwrap().play().unwrap();
+        assert_eq!(replayed.units.len(), contract.get_units(battle_id).unwrap().len());
+        assert_eq!(contract.get_battle_notation(100).err(), Some(HopChainError::BattleNotFound));
+    }
+
+    #[test]
+    fn practice_bot_replies_to_every_move() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        testing_env!(call("alice.near", ONE_NEAR).build());
+        assert_eq!(contract.create_battle(U128(DEFAULT_MIN_BID), Some(Practice::default())).err(), Some(HopChainError::BidOutOfLimits));
+        let battle_id = contract.create_battle(U128(0), Some(Practice { seed: Some(U64(7)) })).unwrap();
+        let battle = contract.get_battle(battle_id).unwrap();
+        assert_eq!(battle.game.second_player, Some(account("hopchain.near")));
+        assert_eq!(battle.game.second_player_units.len(), 3);
+        assert!(contract.find_battle().is_err());
+
+        testing_env!(call("alice.near", ONE_NEAR).build());
+        contract.place_units(battle_id, vec![(1, 2, 0), (2, 2, 1), (1, 2, 2)], None).unwrap();
+        testing_env!(call("alice.near", ONE_NEAR).build());
+        let played = contract.make_move(battle_id, 3, vec![(2, 0), (1, 0)], None).unwrap();
+        assert_eq!(played.outcome.next_move_owner, Some(account("hopchain.near")));
+        let reply = played.bot_reply.unwrap();
+        assert_eq!(reply.next_move_owner, Some(account("alice.near")));
+        let battle = contract.get_battle(battle_id).unwrap();
+        assert_eq!(battle.game.move_count, 2);
+        assert_eq!(battle.game.move_owner(), Some(account("alice.near")));
+        assert_eq!(battle.game.desk.cell(reply.final_position.0, reply.final_position.1).and_then(|c| c.unit).map(|u| u.unit_id), Some(reply.unit_id));
+        let json = serde_json::to_value(&PlayedMove { outcome: played.outcome, bot_reply: Some(reply) }).unwrap();
+        assert_eq!(json["next_move_owner"], "hopchain.near");
+        assert_eq!(json["bot_reply"]["next_move_owner"], "alice.near");
+        assert!(near_sdk::test_utils::get_logs().iter().any(|log| log.contains("\"event\":\"bot_moved\"")));
+
+        testing_env!(call("hopchain.near", 0).build());
+        assert_eq!(contract.make_move(battle_id, 0, vec![(4, 0), (3, 0)], None).err(), Some(HopChainError::Unauthorized));
+    }
+
+    #[test]
+    fn practice_bot_without_legal_moves_resigns() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        testing_env!(call("alice.near", ONE_NEAR).build());
+        let battle_id = contract.create_battle(U128(0), Some(Practice::default())).unwrap();
+        // the bot fills its half, alice walls it in and keeps a spare move
+        let mut battle = contract.get_battle(battle_id).unwrap();
+        battle.game = Game::new(account("alice.near"), 6, 2).unwrap();
+        battle.game.join(account("hopchain.near")).unwrap();
+        battle.game.place_units(vec![(1, 0, 0), (1, 1, 0), (1, 1, 1), (1, 2, 0), (1, 2, 1)], account("alice.near")).unwrap();
+        battle.game.place_units((3..6).flat_map(|x| [(1, x, 0), (1, x, 1)]).collect(), account("hopchain.near")).unwrap();
+        contract.save_battle(&battle);
+
+        testing_env!(call("alice.near", ONE_NEAR).build());
+        let PlayedMove { outcome, bot_reply } = contract.make_move(battle_id, 0, vec![(0, 0), (0, 1)], None).unwrap();
+        assert!(bot_reply.is_none());
+        assert!(outcome.battle_ended && outcome.next_move_owner.is_none());
+        assert_eq!(outcome.winner, Some(account("alice.near")));
+        let battle = contract.get_battle(battle_id).unwrap();
+        assert_eq!((battle.game.current_state, battle.game.move_count), (3, 1));
+        assert!(battle.ended_at.is_some() && contract.finished_battles.iter().any(|id| id == battle_id));
+        assert!(near_sdk::test_utils::get_logs().iter().any(|log| log.contains("\"event\":\"bot_resigned\"")));
+    }
+
+    #[test]
+    fn contracts_called_by_a_user_cannot_spend_the_users_balance() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        testing_env!(call("alice.near", ONE_NEAR).build());
+        contract.deposit();
+        let balance = contract.get_balance(account("alice.near"), None);
+        // alice signed a call of game.near, which calls this contract in turn
+        testing_env!(relayed_call("game.near", "alice.near").build());
+        assert_eq!(contract.create_sponsored_battle(U128(DEFAULT_MIN_BID)).err(), Some(HopChainError::Unauthorized));
+        sponsored_battle(&mut contract);
+        testing_env!(call("bob.near", 2 * ONE_NEAR).build());
+        let open_battle_id = contract.create_battle(U128(DEFAULT_MIN_BID), None).unwrap();
+        testing_env!(relayed_call("game.near", "alice.near").build());
+        assert_eq!(contract.join_sponsored_battle(open_battle_id).err(), Some(HopChainError::Unauthorized));
+        assert_eq!(contract.get_balance(account("alice.near"), None), balance);
+
+        testing_env!(call("alice.near", 0).build());
+        assert_eq!(contract.allow_relayer(account("alice.near")).err(), Some(HopChainError::Unauthorized));
+        testing_env!(call("owner.near", 0).build());
+        contract.disallow_relayer(account("relayer.near")).unwrap();
+        assert!(!contract.is_relayer_allowed(account("relayer.near")));
+        testing_env!(relayed_call("carol.near", "relayer.near").build());
+        assert_eq!(contract.join_sponsored_battle(open_battle_id).err(), Some(HopChainError::Unauthorized));
+    }
+
+    #[test]
+    fn relayer_is_not_treated_as_player() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        let battle_id = sponsored_battle(&mut contract);
+        testing_env!(call("relayer.near", 0).build());
+        let error = contract.place_units(battle_id, vec![(2, 1, 0)], None).unwrap_err();
+        assert_eq!(error, HopChainError::NotYourBattle);
+        assert_eq!(error.to_string(), "E02: This is not your room!");
+    }
+
+    #[test]
+    fn invalid_moves_return_typed_errors() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        let battle_id = sponsored_battle(&mut contract);
+        testing_env!(call("alice.near", 0).build());
+        contract.place_units(battle_id, vec![(2, 1, 0), (1, 2, 0)], None).unwrap();
+        testing_env!(call("bob.near", 0).build());
+        contract.place_units(battle_id, vec![(0, 4, 0)], None).unwrap();
+
+        assert_eq!(contract.make_move(battle_id, 2, vec![(4, 0), (3, 0)], None).err(), Some(HopChainError::NotYourTurn));
+        testing_env!(call("alice.near", 0).build());
+        assert_eq!(contract.make_move(battle_id, 0, vec![(1, 0), (1, -1)], None).err(), Some(HopChainError::OutOfBounds));
+        assert_eq!(contract.make_move(battle_id, 0, vec![(1, 0), (2, 0)], None).err(), Some(HopChainError::CellOccupied));
+        assert_eq!(contract.make_move(battle_id, 0, vec![(1, 0), (1, 3)], None).err(), Some(HopChainError::IllegalHop));
+        assert_eq!(contract.make_move(battle_id, 2, vec![(4, 0), (3, 0)], None).err(), Some(HopChainError::NotYourUnit));
+        assert_eq!(contract.get_battle_info(9).err(), Some(HopChainError::BattleNotFound));
+    }
+
+    #[test]
+    fn unit_registry_follows_moves_and_deaths() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        let battle_id = sponsored_battle(&mut contract);
+        testing_env!(call("alice.near", 0).build());
+        contract.place_units(battle_id, vec![(2, 1, 0), (1, 2, 0)], None).unwrap();
+        testing_env!(call("bob.near", 0).build());
+        contract.place_units(battle_id, vec![(0, 4, 0), (1, 5, 0)], None).unwrap();
+        let units = contract.get_units(battle_id).unwrap();
+        assert_eq!(units.iter().map(|u| (u.unit_id, u.x, u.y)).collect::<Vec<_>>(), vec![(0, 1, 0), (1, 2, 0), (2, 4, 0), (3, 5, 0)]);
+        assert_eq!(units[2].owner, account("bob.near"));
+
+        testing_env!(call("alice.near", 0).build());
+        contract.make_move(battle_id, 0, vec![(1, 0), (3, 0)], None).unwrap();
+
+        let units = contract.get_units(battle_id).unwrap();
+        assert_eq!(units.iter().map(|u| (u.unit_id, u.x, u.y)).collect::<Vec<_>>(), vec![(0, 3, 0), (1, 2, 0), (3, 5, 0)]);
+        assert_eq!(units[1].hp, 25.0);
+        let battle = contract.get_battle(battle_id).unwrap();
+        for entry in battle.game.units.values() {
+            let position = entry.unit.current_position;
+            let cell_unit = battle.game.desk.cell(position.x, position.y).and_then(|c| c.unit).unwrap();
+            assert_eq!((cell_unit.unit_id, cell_unit.stats.hp), (entry.unit.unit_id, entry.unit.stats.hp));
+        }
+    }
+
+    #[test]
+    fn freed_storage_is_refunded_once_to_whoever_paid() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        testing_env!(call("alice.near", 2 * ONE_NEAR).build());
+        let battle_id = contract.create_battle(U128(ONE_NEAR), None).unwrap();
+        testing_env!(call("bob.near", 2 * ONE_NEAR).build());
+        contract.join_battle(battle_id).unwrap();
+        testing_env!(call("alice.near", 0).build());
+        contract.place_units(battle_id, vec![(2, 1, 0), (1, 2, 0)], None).unwrap();
+        testing_env!(call("bob.near", 0).build());
+        contract.place_units(battle_id, vec![(0, 4, 0), (1, 5, 0)], None).unwrap();
+        let (alice_storage, bob_storage) = contract.get_battle(battle_id).map(|b| (b.first_player_storage, b.second_player_storage)).unwrap();
+        let bob_balance = contract.get_balance(account("bob.near"), None).0;
+
+        // the mage of bob dies, its packed bytes are fewer than the move record so alice pays the difference
+        testing_env!(call("alice.near", ONE_NEAR).build());
+        contract.make_move(battle_id, 0, vec![(1, 0), (3, 0)], None).unwrap();
+        let battle = contract.get_battle(battle_id).unwrap();
+        assert_eq!(contract.get_balance(account("bob.near"), None).0, bob_balance);
+        assert_eq!(battle.second_player_storage, bob_storage);
+        assert!(battle.first_player_storage > alice_storage);
+
+        // prune refunds at most the bytes still held for each player
+        resolve(&mut contract, battle_id, BattleOutcome::FirstPlayerWins);
+        let balances = |contract: &Contract| ["alice.near", "bob.near"].map(|a| contract.get_balance(account(a), None).0);
+        let before = balances(&contract);
+        testing_env!(call("carol.near", 0).build());
+        contract.prune(10).unwrap();
+        let after = balances(&contract);
+        assert!(after[0] > before[0] && after[1] > before[1]);
+        assert!(after[0] - before[0] <= battle.first_player_storage as Balance * env::storage_byte_cost());
+        assert!(after[1] - before[1] <= battle.second_player_storage as Balance * env::storage_byte_cost());
+    }
+
+    #[test]
+    fn storage_freed_by_a_move_is_split_between_owners_of_the_killed_units() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        testing_env!(call("alice.near", 2 * ONE_NEAR).build());
+        let battle_id = contract.create_battle(U128(ONE_NEAR), None).unwrap();
+        testing_env!(call("bob.near", 2 * ONE_NEAR).build());
+        contract.join_battle(battle_id).unwrap();
+        testing_env!(call("alice.near", 0).build());
+        contract.place_units(battle_id, vec![(2, 1, 0), (0, 2, 0)], None).unwrap();
+        testing_env!(call("bob.near", 0).build());
+        contract.place_units(battle_id, vec![(0, 4, 0), (1, 5, 0)], None).unwrap();
+        let (alice_storage, bob_storage) = contract.get_battle(battle_id).map(|b| (b.first_player_storage, b.second_player_storage)).unwrap();
+        let balances = |contract: &Contract| ["alice.near", "bob.near"].map(|a| contract.get_balance(account(a), None).0);
+        let before = balances(&contract);
+
+        // the skeleton of alice hops over her own mage and kills it together with the mage of bob
+        testing_env!(call("alice.near", 0).build());
+        let initial_storage = env::storage_usage();
+        let outcome = contract.make_move(battle_id, 0, vec![(1, 0), (3, 0)], None).unwrap().outcome;
+        assert_eq!(outcome.killed_units, vec![2, 1]);
+        let freed = initial_storage - env::storage_usage();
+        let battle = contract.get_battle(battle_id).unwrap();
+        let released = [alice_storage - battle.first_player_storage, bob_storage - battle.second_player_storage];
+        assert!(freed > 0 && released[0].abs_diff(released[1]) <= 1);
+        assert_eq!(released[0] + released[1], freed);
+        let after = balances(&contract);
+        assert_eq!(after[0] - before[0], released[0] as Balance * env::storage_byte_cost());
+        assert_eq!(after[1] - before[1], released[1] as Balance * env::storage_byte_cost());
+    }
+
+    #[test]
+    fn move_timeout_does_not_count_time_spent_paused() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        contract.set_config(Config { move_timeout: U64(100), ..Config::default() }).unwrap();
+        let battle_id = sponsored_battle(&mut contract);
+        testing_env!(call("alice.near", 0).build());
+        contract.place_units(battle_id, vec![(2, 1, 0)], None).unwrap();
+        testing_env!(call("bob.near", 0).build());
+        contract.place_units(battle_id, vec![(0, 4, 0)], None).unwrap();
+
+        testing_env!(call("owner.near", 0).block_timestamp(50).build());
+        contract.pause().unwrap();
+        testing_env!(call("bob.near", 0).block_timestamp(400).build());
+        assert_eq!(contract.claim_timeout(battle_id).err(), Some(HopChainError::Paused));
+        testing_env!(call("owner.near", 0).block_timestamp(500).build());
+        contract.unpause().unwrap();
+        testing_env!(call("bob.near", ONE_NEAR).block_timestamp(550).build());
+        assert_eq!(contract.claim_timeout(battle_id).err(), Some(HopChainError::TimeoutNotReached));
+        testing_env!(call("bob.near", ONE_NEAR).block_timestamp(601).build());
+        contract.claim_timeout(battle_id).unwrap();
+        assert_eq!(contract.get_battle(battle_id).unwrap().game.winner, Some(account("bob.near")));
+    }
+
+    #[test]
+    fn delegate_deposit_goes_back_to_the_delegate() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        testing_env!(call("alice.near", 2 * ONE_NEAR).build());
+        let battle_id = contract.create_battle(U128(ONE_NEAR), None).unwrap();
+        testing_env!(call("bob.near", 2 * ONE_NEAR).build());
+        contract.join_battle(battle_id).unwrap();
+        testing_env!(call("alice.near", ONE_NEAR).build());
+        contract.authorize_delegate(account("dave.near"), Some(battle_id), None).unwrap();
+        let alice_balance = contract.get_balance(account("alice.near"), None).0;
+
+        testing_env!(call("dave.near", ONE_NEAR).build());
+        assert_eq!(contract.place_units(battle_id, vec![(2, 1, 0)], Some(account("bob.near"))).err(), Some(HopChainError::InvalidDelegation));
+        contract.place_units(battle_id, vec![(2, 1, 0)], Some(account("alice.near"))).unwrap();
+        // less the storage of the delegate's new balance
+        assert!(ONE_NEAR - contract.get_balance(account("dave.near"), None).0 < ONE_NEAR / 100);
+        assert!(contract.get_balance(account("alice.near"), None).0 < alice_balance);
+        assert_eq!(contract.get_battle(battle_id).unwrap().game.first_player_units, vec![0]);
+    }
+
+    #[test]
+    fn fees_are_collected_and_withdrawn_by_the_owner() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        assert_eq!(contract.set_fee(MAX_FEE_BASIS_POINTS + 1).err(), Some(HopChainError::InvalidConfig));
+        contract.set_fee(250).unwrap();
+        contract.set_config(Config { move_timeout: U64(100), ..Config::default() }).unwrap();
+        testing_env!(call("alice.near", 0).build());
+        assert_eq!(contract.set_fee(0).err(), Some(HopChainError::Unauthorized));
+        assert_eq!(contract.get_fee(), 250);
+
+        let fee = 2 * ONE_NEAR * 250 / MAX_FEE_BASIS_POINTS as u128;
+        let mut battle_ids = vec![];
+        for _ in 0..2 {
+            testing_env!(call("alice.near", 2 * ONE_NEAR).build());
+            let battle_id = contract.create_battle(U128(ONE_NEAR), None).unwrap();
+            testing_env!(call("bob.near", 2 * ONE_NEAR).build());
+            contract.join_battle(battle_id).unwrap();
+            battle_ids.push(battle_id);
+        }
+        resolve(&mut contract, battle_ids[0], BattleOutcome::FirstPlayerWins);
+        assert_eq!(contract.get_treasury(None), U128(fee));
+        testing_env!(call("alice.near", 0).build());
+        contract.place_units(battle_ids[1], vec![(2, 1, 0)], None).unwrap();
+        testing_env!(call("bob.near", 0).build());
+        contract.place_units(battle_ids[1], vec![(0, 4, 0)], None).unwrap();
+        testing_env!(call("bob.near", 0).block_timestamp(200).build());
+        contract.claim_timeout(battle_ids[1]).unwrap();
+        assert_eq!(contract.get_treasury(None), U128(2 * fee));
+        // a draw returns the bids without a fee
+        testing_env!(call("alice.near", 2 * ONE_NEAR).build());
+        let battle_id = contract.create_battle(U128(ONE_NEAR), None).unwrap();
+        testing_env!(call("bob.near", 2 * ONE_NEAR).build());
+        contract.join_battle(battle_id).unwrap();
+        resolve(&mut contract, battle_id, BattleOutcome::Draw);
+        assert_eq!(contract.get_treasury(None), U128(2 * fee));
+
+        testing_env!(call("alice.near", 0).build());
+        assert_eq!(contract.withdraw_fees(U128(fee), account("alice.near"), None).err(), Some(HopChainError::Unauthorized));
+        testing_env!(call("owner.near", 0).build());
+        assert_eq!(contract.withdraw_fees(U128(3 * fee), account("owner.near"), None).err(), Some(HopChainError::InsufficientBalance));
+        contract.withdraw_fees(U128(fee), account("owner.near"), None).unwrap();
+        assert_eq!(contract.get_treasury(None), U128(fee));
+        callback(PromiseResult::Failed);
+        assert!(!contract.on_withdraw_fees(U128(fee), None));
+        assert_eq!(contract.get_treasury(None), U128(2 * fee));
+    }
+
+    #[test]
+    fn failed_withdrawals_restore_the_balance() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        contract.allow_token(account("usdc.near")).unwrap();
+        testing_env!(call("alice.near", ONE_NEAR).build());
+        contract.deposit();
+        ft_transfer_call(&mut contract, "usdc.near", "alice.near", DEFAULT_MIN_BID, r#"{"action": "create_battle"}"#).unwrap();
+        let battle_id = contract.last_player_battle_id.get(&account("alice.near")).unwrap();
+        testing_env!(call("alice.near", 0).build());
+        contract.cancel_battle(battle_id).unwrap();
+
+        let usdc = Some(account("usdc.near"));
+        for token_id in [None, usdc] {
+            testing_env!(call("alice.near", 0).build());
+            let balance = contract.get_balance(account("alice.near"), token_id.clone()).0;
+            assert_eq!(contract.withdraw(U128(balance + 1), token_id.clone()).err(), Some(HopChainError::InsufficientBalance));
+            contract.withdraw(U128(balance), token_id.clone()).unwrap();
+            assert_eq!(contract.get_balance(account("alice.near"), token_id.clone()), U128(0));
+            callback(PromiseResult::Failed);
+            assert!(!contract.on_withdraw(account("alice.near"), U128(balance), token_id.clone()));
+            assert_eq!(contract.get_balance(account("alice.near"), token_id.clone()), U128(balance));
+
+            testing_env!(call("alice.near", 0).build());
+            contract.withdraw(U128(balance), token_id.clone()).unwrap();
+            callback(PromiseResult::Successful(vec![]));
+            assert!(contract.on_withdraw(account("alice.near"), U128(balance), token_id.clone()));
+            assert_eq!(contract.get_balance(account("alice.near"), token_id), U128(0));
+        }
+    }
+
+    #[test]
+    fn overpayment_too_small_for_a_balance_is_sent_back() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        let byte_cost = env::storage_byte_cost();
+        let sent_back = |receiver: &str| near_sdk::test_utils::get_created_receipts().into_iter()
+            .filter(|receipt| receipt.receiver_id == account(receiver))
+            .flat_map(|receipt| receipt.actions)
+            .map(|action| match action {
+                near_sdk::mock::VmAction::Transfer { deposit } => deposit,
+                _ => 0
+            })
+            .sum::<Balance>();
+        // storage of a create, measured with a balance to pay from
+        for player in ["alice.near", "bob.near"] {
+            testing_env!(call(player, ONE_NEAR).build());
+            contract.deposit();
+        }
+        testing_env!(call("alice.near", ONE_NEAR).build());
+        let initial_storage = env::storage_usage();
+        let battle_id = contract.create_battle(U128(ONE_NEAR), None).unwrap();
+        let create_cost = (env::storage_usage() - initial_storage) as Balance * byte_cost;
+        testing_env!(call("bob.near", ONE_NEAR).build());
+        contract.join_battle(battle_id).unwrap();
+
+        testing_env!(call("carol.near", ONE_NEAR + create_cost + 5).build());
+        let battle_id = contract.create_battle(U128(ONE_NEAR), None).unwrap();
+        assert_eq!(sent_back("carol.near"), 5);
+        assert!(!contract.balances.contains_key(&(account("carol.near"), None)));
+        // the join frees storage, it goes back to carol who paid for it
+        testing_env!(call("dan.near", ONE_NEAR + 7).build());
+        contract.join_battle(battle_id).unwrap();
+        assert_eq!(sent_back("dan.near"), 7);
+        assert!(!contract.balances.contains_key(&(account("dan.near"), None)));
+        // larger overpayment pays for its own balance
+        testing_env!(call("erin.near", 2 * ONE_NEAR).build());
+        contract.create_battle(U128(ONE_NEAR), None).unwrap();
+        assert_eq!(sent_back("erin.near"), 0);
+        assert!(contract.get_balance(account("erin.near"), None).0 > ONE_NEAR - ONE_NEAR / 100);
+    }
+
+    /// context of a callback of this contract receiving `result`
+    fn callback(result: PromiseResult) {
+        testing_env!(call("hopchain.near", 0).build(), near_sdk::VMConfig::test(), near_sdk::RuntimeFeesConfig::test(), Default::default(), vec![result]);
+    }
+
+    #[test]
+    fn session_is_recorded_only_after_its_key_is_added() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        let battle_id = sponsored_battle(&mut contract);
+        let public_key: PublicKey = "ed25519:6E8sCci9badyRkXb3JoRpBj5p8C6Tw41ELDZoiihKEtp".parse().unwrap();
+        let session = || Session { player: account("alice.near"), battle_id, expires_at: U64(100) };
+        testing_env!(call("alice.near", ONE_NEAR).build());
+        contract.authorize_session(public_key.clone(), battle_id, U64(100)).unwrap();
+        assert!(contract.get_session(public_key.clone()).is_none());
+
+        // e.g. the key is a full access key of the contract account already
+        callback(PromiseResult::Failed);
+        assert!(!contract.on_session_key_added(public_key.clone(), session(), U128(ONE_NEAR)));
+        assert!(contract.get_session(public_key.clone()).is_none());
+        assert!(near_sdk::test_utils::get_created_receipts().is_empty());
+        let refund = contract.get_balance(account("alice.near"), None).0;
+        assert!(ONE_NEAR - refund < ONE_NEAR / 100);
+
+        callback(PromiseResult::Successful(vec![]));
+        assert!(contract.on_session_key_added(public_key.clone(), session(), U128(ONE_NEAR)));
+        let balance = contract.get_balance(account("alice.near"), None).0;
+        assert!(balance > refund + ONE_NEAR / 2);
+
+        let mut context = call("hopchain.near", 0);
+        context.signer_account_pk(public_key.clone());
+        testing_env!(context.build());
+        contract.place_units(battle_id, vec![(2, 1, 0)], None).unwrap();
+        // failed calls burn gas of the allowance too
+        assert_eq!(contract.make_move(battle_id, 0, vec![(1, 0), (3, 0)], None).err(), Some(HopChainError::WrongState));
+
+        testing_env!(call("bob.near", 0).build());
+        assert_eq!(contract.revoke_session(public_key.clone()).err(), Some(HopChainError::Unauthorized));
+        testing_env!(call("alice.near", 0).build());
+        contract.revoke_session(public_key.clone()).unwrap();
+        assert!(contract.get_session(public_key).is_none());
+        // only the storage of the session comes back
+        let refund = contract.get_balance(account("alice.near"), None).0 - balance;
+        assert!(refund > 0 && refund < ONE_NEAR / 100);
+    }
+
+    /// desk as the first version stored it: cells column by column, each with its position
+    fn legacy_desk(units: &[(i8, i8, Unit)]) -> LegacyDesk {
+        let mut cells = vec![];
+        for x in 0..7 {
+            for y in 0..4 {
+                let unit = units.iter().find(|(ux, uy, _)| (*ux, *uy) == (x, y)).map(|(_, _, unit)| *unit);
+                cells.push(Cell { unit, position: Vector2::new(x, y, x * 4 + y) });
+            }
+        }
+        LegacyDesk { cells, x_range: 7, y_range: 4 }
+    }
+
+    #[test]
+    fn compact_desk_round_trips_and_saves_storage() {
+        let mut units = vec![];
+        for unit_id in 0..12 {
+            let x = if unit_id < 6 { unit_id % 3 } else { 4 + unit_id % 3 };
+            let mut unit = Unit::new(1 + unit_id % 5, Unit::get_unit_type_by_id(unit_id % 3).unwrap(), unit_id);
+            unit.stats.hp -= 0.5;
+            units.push((x, unit_id % 2 * 2, unit));
+        }
+        for count in [0, 4, 12] {
+            let legacy = legacy_desk(&units[..count]);
+            let legacy_bytes = legacy.try_to_vec().unwrap().len();
+            let desk = Desk::from(legacy);
+            let bytes = desk.try_to_vec().unwrap();
+            println!("7x4 desk with {} units: {} bytes, was {} bytes, {}% saved",
+                count, bytes.len(), legacy_bytes, 100 - bytes.len() * 100 / legacy_bytes);
+            assert_eq!(bytes.len(), 10 + 7 * count);
+            assert!(bytes.len() * 3 < legacy_bytes);
+
+            let restored = Desk::try_from_slice(&bytes).unwrap();
+            for (x, y, unit) in &units[..count] {
+                let stored = restored.cell(*x, *y).and_then(|c| c.unit).unwrap();
+                assert_eq!((stored.unit_id, stored.level, stored.stats.hp, stored.stats.damage, stored.stats.max_hp),
+                    (unit.unit_id, unit.level, unit.stats.hp, unit.stats.damage, unit.stats.max_hp));
+                assert_eq!((stored.current_position.x, stored.current_position.y), (*x, *y));
+            }
+            assert_eq!(restored.cells().iter().filter(|c| c.unit.is_some()).count(), count);
+        }
+    }
+
+    #[test]
+    fn migrate_converts_legacy_desks() {
+        testing_env!(call("hopchain.near", 0).build());
+        let mut battles = Vector::new(StorageKey::Battles);
+        battles.push(&BattleV1 {
+            battle_id: 0,
+            first_player: Some(account("alice.near")),
+            second_player: Some(account("bob.near")),
+            desk: legacy_desk(&[(1, 0, Unit::new(1, UnitType::Skeleton, 0)), (4, 3, Unit::new(1, UnitType::Mage, 1))]),
+            bid: DEFAULT_MIN_BID,
+            first_player_units: vec![0],
+            second_player_units: vec![1],
+            current_state: 2,
+            last_unit_id: 2,
+            current_move_owner: 0,
+            winner: None
+        });
+        env::state_write(&ContractV1 {
+            battles,
+            battles_of_player: LookupMap::new(StorageKey::BattleOfPlayer),
+            opened_battles: Vector::new(StorageKey::OpenedBattles),
+            last_player_battle_id: LookupMap::new(StorageKey::LastBattleOfPlayer),
+        });
+        let contract = Contract::migrate(Some(account("owner.near")));
+
+        let units = contract.get_units(0).unwrap();
+        assert_eq!(units.iter().map(|u| (u.unit_id, u.x, u.y)).collect::<Vec<_>>(), vec![(0, 1, 0), (1, 4, 3)]);
+        assert_eq!(units[1].owner, account("bob.near"));
+        let battle = contract.get_battle(0).unwrap();
+        let mage = battle.game.desk.cell(4, 3).and_then(|c| c.unit).unwrap();
+        assert_eq!((mage.unit_id, mage.current_position.x, mage.current_position.y, mage.current_position.id), (1, 4, 3, 25));
+        assert_eq!(contract.get_state_version(), STATE_VERSION);
+        assert!(contract.get_battle_notation(0).unwrap().ends_with("\nS@b1\nm@e4\n"));
+        // the battle is keyed by its id now, its entry in the old list is gone
+        let mut old_key = StorageKey::Battles.try_to_vec().unwrap();
+        old_key.extend(0u64.to_le_bytes());
+        assert!(!env::storage_has_key(&old_key));
+        assert_eq!(contract.next_battle_id, 1);
+    }
+
+    #[test]
+    fn battle_ids_go_past_the_range_of_a_byte() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        testing_env!(call("alice.near", 300 * ONE_NEAR).build());
+        contract.deposit();
+        testing_env!(call("alice.near", DEFAULT_MIN_BID).build());
+        let battle_ids: Vec<u32> = (0..300).map(|_| contract.create_battle(U128(DEFAULT_MIN_BID), None).unwrap()).collect();
+        assert_eq!(battle_ids[299], 299);
+        testing_env!(call("bob.near", ONE_NEAR).build());
+        contract.join_battle(200).unwrap();
+        assert_eq!(contract.get_battle_info(200).unwrap().second_player, Some(account("bob.near")));
+        assert_eq!(contract.get_battle_info(199).unwrap().second_player, None);
+        assert_eq!(contract.get_battle_info(300).err(), Some(HopChainError::BattleNotFound));
+    }
+
+    /// property names of `definition` in the published schema
+    pub(crate) fn schema_properties(definition: &str) -> Vec<String> {
+        let schema: serde_json::Value = serde_json::from_str(include_str!("../schema/views.schema.json")).unwrap();
+        let mut properties: Vec<String> = schema["definitions"][definition]["properties"]
+            .as_object().unwrap().keys().cloned().collect();
+        properties.sort();
+        properties
+    }
+
+    pub(crate) fn json_keys(value: &serde_json::Value) -> Vec<String> {
+        let mut keys: Vec<String> = value.as_object().unwrap().keys().cloned().collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn battle_view_matches_schema() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        let battle_id = sponsored_battle(&mut contract);
+        testing_env!(call("alice.near", 0).build());
+        contract.place_units(battle_id, vec![(2, 1, 0), (1, 2, 0)], None).unwrap();
+        testing_env!(call("bob.near", 0).build());
+        contract.place_units(battle_id, vec![(0, 4, 0)], None).unwrap();
+
+        let view = serde_json::to_value(contract.get_battle_info(battle_id).unwrap()).unwrap();
+        assert_eq!(json_keys(&view), schema_properties("BattleView"));
+        assert_eq!(view["bid"], serde_json::json!(DEFAULT_MIN_BID.to_string()));
+        assert_eq!(view["move_owner"], serde_json::json!("alice.near"));
+        let cells = view["cells"].as_array().unwrap();
+        assert_eq!(cells.len(), 3);
+        assert_eq!(json_keys(&cells[0]), schema_properties("CellView"));
+        let mage = cells.iter().find(|c| c["unit_id"] == 2).unwrap();
+        assert_eq!((mage["x"].clone(), mage["y"].clone()), (serde_json::json!(4), serde_json::json!(0)));
+        assert_eq!(mage["owner"], serde_json::json!("bob.near"));
+        assert_eq!(mage["unit_type"], serde_json::json!("Mage"));
+
+        let units = serde_json::to_value(contract.get_units(battle_id).unwrap()).unwrap();
+        assert_eq!(json_keys(&units[0]), schema_properties("UnitView"));
+
+        let battle = contract.get_battle(battle_id).unwrap();
+        let summary = serde_json::to_value(BattleSummary::from(&battle)).unwrap();
+        assert_eq!(json_keys(&summary), schema_properties("BattleSummary"));
+    }
+
+    pub(crate) fn resolve(contract: &mut Contract, battle_id: u32, outcome: BattleOutcome) {
+        testing_env!(call("owner.near", ONE_NEAR).build());
+        contract.resolve_battle(battle_id, outcome).unwrap();
+    }
+
+    #[test]
+    fn sponsored_battle_is_created_by_the_player_and_refunds_sponsor() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        testing_env!(call("relayer.near", ONE_NEAR).build());
+        contract.deposit();
+        testing_env!(relayed_call("alice.near", "relayer.near").build());
+        assert_eq!(contract.create_sponsored_battle(U128(DEFAULT_MIN_BID)).err(), Some(HopChainError::Unauthorized));
+        testing_env!(call("owner.near", 0).build());
+        contract.allow_relayer(account("relayer.near")).unwrap();
+        testing_env!(call("relayer.near", 0).build());
+        assert_eq!(contract.create_sponsored_battle(U128(DEFAULT_MIN_BID)).err(), Some(HopChainError::Unauthorized));
+        testing_env!(relayed_call("alice.near", "relayer.near").build());
+        let battle_id = contract.create_sponsored_battle(U128(DEFAULT_MIN_BID)).unwrap();
+        let balance = contract.get_balance(account("relayer.near"), None).0;
+
+        testing_env!(relayed_call("alice.near", "relayer.near").build());
+        contract.cancel_battle(battle_id).unwrap();
+
+        assert!(contract.get_balance(account("relayer.near"), None).0 > balance);
+        assert_eq!(contract.get_balance(account("alice.near"), None).0, 0);
+    }
+
+    #[test]
+    fn token_battles_are_created_joined_and_paid_in_tokens() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 500);
+        contract.allow_token(account("usdc.near")).unwrap();
+        let bid = DEFAULT_MIN_BID;
+        for player in ["alice.near", "bob.near"] {
+            testing_env!(call(player, ONE_NEAR).build());
+            contract.deposit();
+        }
+        let create = r#"{"action": "create_battle"}"#;
+        // rejected transfers fail the call, so the token contract returns all the tokens
+        assert_eq!(ft_transfer_call(&mut contract, "dai.near", "alice.near", bid, create), Err(HopChainError::TokenNotAllowed));
+        assert_eq!(ft_transfer_call(&mut contract, "usdc.near", "alice.near", 0, create), Err(HopChainError::BidOutOfLimits));
+        assert_eq!(ft_transfer_call(&mut contract, "usdc.near", "alice.near", bid, "create_battle"), Err(HopChainError::InvalidMessage));
+        assert_eq!(ft_transfer_call(&mut contract, "usdc.near", "alice.near", bid, r#"{"action": "leave_battle"}"#), Err(HopChainError::InvalidMessage));
+        assert_eq!(contract.next_battle_id, 0);
+        testing_env!(call("owner.near", 0).build());
+        contract.pause().unwrap();
+        assert_eq!(ft_transfer_call(&mut contract, "usdc.near", "alice.near", bid, create), Err(HopChainError::Paused));
+        testing_env!(call("owner.near", 0).build());
+        contract.unpause().unwrap();
+
+        assert_eq!(ft_transfer_call(&mut contract, "usdc.near", "alice.near", bid, create), Ok(0));
+        let battle_id = contract.last_player_battle_id.get(&account("alice.near")).unwrap();
+        assert_eq!(contract.get_battle_info(battle_id).unwrap().token, Some(account("usdc.near")));
+        testing_env!(call("carol.near", 2 * ONE_NEAR).build());
+        let near_battle_id = contract.create_battle(U128(bid), None).unwrap();
+        let join = |battle_id: u32| format!(r#"{{"action": "join_battle", "battle_id": {}}}"#, battle_id);
+        assert_eq!(ft_transfer_call(&mut contract, "usdc.near", "bob.near", bid, &join(near_battle_id)), Err(HopChainError::WrongToken));
+        assert_eq!(ft_transfer_call(&mut contract, "usdc.near", "bob.near", bid - 1, &join(battle_id)), Err(HopChainError::InsufficientDeposit(bid)));
+        testing_env!(call("bob.near", ONE_NEAR).build());
+        assert_eq!(contract.join_battle(battle_id).err(), Some(HopChainError::WrongToken));
+        assert_eq!(ft_transfer_call(&mut contract, "usdc.near", "bob.near", bid + 7, &join(battle_id)), Ok(7));
+
+        resolve(&mut contract, battle_id, BattleOutcome::SecondPlayerWins);
+        let fee = 2 * bid * 500 / MAX_FEE_BASIS_POINTS as u128;
+        assert_eq!(contract.get_balance(account("bob.near"), Some(account("usdc.near"))), U128(2 * bid - fee));
+        assert_eq!(contract.get_treasury(Some(account("usdc.near"))), U128(fee));
+        assert_eq!(contract.get_treasury(None), U128(0));
+        testing_env!(call("bob.near", 0).build());
+        contract.withdraw(U128(2 * bid - fee), Some(account("usdc.near"))).unwrap();
+        assert_eq!(contract.get_balance(account("bob.near"), Some(account("usdc.near"))), U128(0));
+    }
+}
@@ -0,0 +1,627 @@
+//! Tournaments: an organizer sets the entry fee, number of players, bracket and payout split,
+//! players register by paying the entry fee and the contract spawns the battles of every round.
+
+use super::*;
+
+/// keeps the battles of a round within the gas of a single call
+const MAX_TOURNAMENT_PLAYERS: u8 = 16;
+/// points for a win or a bye, a draw gives half of it
+const POINTS_FOR_WIN: u16 = 2;
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum Bracket {
+    /// losers drop out until one player is left, a draw lets the first player of the battle through
+    SingleElimination,
+    /// every player plays every round against a player with similar points
+    Swiss { rounds: u8 },
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum TournamentState {
+    Registration,
+    Running,
+    Finished,
+    Cancelled,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct TournamentPlayer {
+    account_id: AccountId,
+    points: u16,
+    /// lost in single elimination or left the running tournament
+    eliminated: bool,
+    /// round the player dropped out in, later rounds rank higher
+    eliminated_in: u8,
+    had_bye: bool,
+}
+
+/// Battle of the current round between two players given by their index in `players`
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy)]
+pub struct Pairing {
+    battle_id: u32,
+    first: u8,
+    second: u8,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Tournament {
+    organizer: AccountId,
+    entry_fee: Balance,
+    max_players: u8,
+    bracket: Bracket,
+    /// share of the prize pool of each place in basis points, adds up to 10000
+    payout: Vec<u16>,
+    state: TournamentState,
+    /// in registration order, which is also the seeding
+    players: Vec<TournamentPlayer>,
+    /// 0 until the tournament starts
+    round: u8,
+    pairings: Vec<Pairing>,
+    /// every pair of players that already met, by index
+    played: Vec<(u8, u8)>,
+    prize_pool: Balance,
+    /// accounts by final place, set when the tournament is finished
+    standings: Vec<AccountId>,
+}
+
+impl Tournament {
+    fn index_of(&self, account_id: &AccountId) -> Option<usize> {
+        self.players.iter().position(|p| &p.account_id == account_id)
+    }
+
+    fn has_played(&self, a: u8, b: u8) -> bool {
+        self.played.contains(&(a.min(b), a.max(b)))
+    }
+
+    /// players ordered from the first place to the last
+    fn ranking(&self) -> Vec<usize> {
+        let mut ranking: Vec<usize> = (0..self.players.len()).collect();
+        match self.bracket {
+            Bracket::SingleElimination => ranking.sort_by_key(|&i| {
+                let p = &self.players[i];
+                (p.eliminated, std::cmp::Reverse(p.eliminated_in))
+            }),
+            Bracket::Swiss { .. } => ranking.sort_by_key(|&i| {
+                let p = &self.players[i];
+                (p.eliminated, std::cmp::Reverse(p.points))
+            }),
+        }
+        ranking
+    }
+
+    /// Pairs players of the next round, returns pairs of player indexes.
+    /// With an odd number of players one of them gets a bye and the points of a win.
+    fn next_pairs(&mut self) -> Vec<(u8, u8)> {
+        let mut waiting: Vec<u8> = match self.bracket {
+            Bracket::SingleElimination => (0..self.players.len() as u8).filter(|&i| !self.players[i as usize].eliminated).collect(),
+            Bracket::Swiss { .. } => self.ranking().into_iter().filter(|&i| !self.players[i].eliminated).map(|i| i as u8).collect(),
+        };
+        if waiting.len() % 2 == 1 {
+            // the best seed skips a single elimination round, the lowest ranked player without a bye a swiss one
+            let bye = match self.bracket {
+                Bracket::SingleElimination => 0,
+                Bracket::Swiss { .. } => waiting.iter().rposition(|&i| !self.players[i as usize].had_bye).unwrap_or(waiting.len() - 1),
+            };
+            let player = &mut self.players[waiting.remove(bye) as usize];
+            player.had_bye = true;
+            player.points += POINTS_FOR_WIN;
+        }
+        let mut pairs = vec![];
+        while !waiting.is_empty() {
+            let first = waiting.remove(0);
+            let opponent = match self.bracket {
+                Bracket::SingleElimination => 0,
+                Bracket::Swiss { .. } => waiting.iter().position(|&i| !self.has_played(first, i)).unwrap_or(0),
+            };
+            pairs.push((first, waiting.remove(opponent)));
+        }
+        pairs
+    }
+
+    fn is_over(&self) -> bool {
+        let remaining = self.players.iter().filter(|p| !p.eliminated).count();
+        match self.bracket {
+            Bracket::SingleElimination => remaining <= 1,
+            Bracket::Swiss { rounds } => remaining <= 1 || self.round >= rounds,
+        }
+    }
+}
+
+/// Tournament as it is returned by `get_tournament`, described by `schema/views.schema.json`
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TournamentView {
+    pub tournament_id: u32,
+    pub organizer: AccountId,
+    pub entry_fee: U128,
+    pub max_players: u8,
+    pub bracket: Bracket,
+    pub payout: Vec<u16>,
+    pub state: TournamentState,
+    pub players: Vec<TournamentPlayerView>,
+    pub round: u8,
+    /// battles of the current round
+    pub battles: Vec<u32>,
+    pub prize_pool: U128,
+    pub standings: Vec<AccountId>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TournamentPlayerView {
+    pub account_id: AccountId,
+    pub points: u16,
+    pub eliminated: bool,
+}
+
+impl TournamentView {
+    fn new(tournament_id: u32, t: &Tournament) -> Self {
+        Self {
+            tournament_id,
+            organizer: t.organizer.clone(),
+            entry_fee: U128(t.entry_fee),
+            max_players: t.max_players,
+            bracket: t.bracket,
+            payout: t.payout.clone(),
+            state: t.state,
+            players: t.players.iter().map(|p| TournamentPlayerView {
+                account_id: p.account_id.clone(),
+                points: p.points,
+                eliminated: p.eliminated
+            }).collect(),
+            round: t.round,
+            battles: t.pairings.iter().map(|p| p.battle_id).collect(),
+            prize_pool: U128(t.prize_pool),
+            standings: t.standings.clone(),
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Creates a tournament organized by the caller, storage is paid from the attached deposit
+    /// or the caller's balance. `payout` is the share of the prize pool of the first, second...
+    /// place in basis points and must add up to 10000.
+    #[payable]
+    #[handle_result]
+    pub fn create_tournament(&mut self, entry_fee: U128, max_players: u8, bracket: Bracket, payout: Vec<u16>) -> Result<u32, HopChainError> {
+        self.assert_not_paused()?;
+        let rounds_valid = match bracket {
+            Bracket::SingleElimination => true,
+            Bracket::Swiss { rounds } => rounds >= 1 && rounds < max_players,
+        };
+        if !(2..=MAX_TOURNAMENT_PLAYERS).contains(&max_players) || !rounds_valid || payout.is_empty()
+            || payout.len() > max_players as usize || payout.iter().map(|&p| p as u32).sum::<u32>() != MAX_FEE_BASIS_POINTS as u32 {
+            return Err(HopChainError::InvalidConfig);
+        }
+        let organizer = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        let tournament_id = self.next_tournament_id;
+        self.next_tournament_id += 1;
+        self.tournaments.insert(&tournament_id, &Tournament {
+            organizer: organizer.clone(),
+            entry_fee: entry_fee.0,
+            max_players,
+            bracket,
+            payout,
+            state: TournamentState::Registration,
+            players: vec![],
+            round: 0,
+            pairings: vec![],
+            played: vec![],
+            prize_pool: 0,
+            standings: vec![],
+        });
+        self.settle_storage(&organizer, initial_storage, env::attached_deposit())?;
+        Self::emit_event("tournament_created", serde_json::json!({ "tournament_id": tournament_id, "organizer": organizer }));
+        Ok(tournament_id)
+    }
+
+    /// registers the caller, attached deposit must cover the entry fee and storage,
+    /// everything above that is credited to the caller's balance
+    #[payable]
+    #[handle_result]
+    pub fn register_for_tournament(&mut self, tournament_id: u32) -> Result<(), HopChainError> {
+        self.assert_not_paused()?;
+        let mut tournament = self.get_tournament_state(tournament_id)?;
+        let player = env::predecessor_account_id();
+        let deposit = env::attached_deposit();
+        if tournament.state != TournamentState::Registration || tournament.index_of(&player).is_some() {
+            return Err(HopChainError::WrongState);
+        }
+        if tournament.players.len() >= tournament.max_players as usize {
+            return Err(HopChainError::BattleFull);
+        }
+        if deposit < tournament.entry_fee {
+            return Err(HopChainError::InsufficientDeposit(tournament.entry_fee));
+        }
+        let initial_storage = env::storage_usage();
+        tournament.prize_pool += tournament.entry_fee;
+        tournament.players.push(TournamentPlayer {
+            account_id: player.clone(),
+            points: 0,
+            eliminated: false,
+            eliminated_in: 0,
+            had_bye: false,
+        });
+        self.tournaments.insert(&tournament_id, &tournament);
+        self.settle_storage(&player, initial_storage, deposit - tournament.entry_fee)?;
+        Ok(())
+    }
+
+    /// Before the start the entry fee goes back to the caller's balance. In a running tournament
+    /// the caller forfeits: the battle of the current round is won by the opponent
+    /// and the caller isn't paired any more.
+    #[handle_result]
+    pub fn leave_tournament(&mut self, tournament_id: u32) -> Result<(), HopChainError> {
+        let mut tournament = self.get_tournament_state(tournament_id)?;
+        let player = env::predecessor_account_id();
+        let index = tournament.index_of(&player).ok_or(HopChainError::Unauthorized)?;
+        let initial_storage = env::storage_usage();
+        match tournament.state {
+            TournamentState::Registration => {
+                tournament.players.remove(index);
+                tournament.prize_pool -= tournament.entry_fee;
+                self.internal_deposit(&player, &None, tournament.entry_fee);
+            },
+            TournamentState::Running if !tournament.players[index].eliminated => {
+                tournament.players[index].eliminated = true;
+                tournament.players[index].eliminated_in = tournament.round;
+                let pairing = tournament.pairings.iter().find(|p| p.first as usize == index || p.second as usize == index).copied();
+                if let Some(pairing) = pairing {
+                    let opponent = if pairing.first as usize == index { pairing.second } else { pairing.first };
+                    self.forfeit_battle(pairing.battle_id, &tournament.players[opponent as usize].account_id)?;
+                }
+            },
+            _ => return Err(HopChainError::WrongState)
+        }
+        self.tournaments.insert(&tournament_id, &tournament);
+        self.settle_storage(&player, initial_storage, 0)?;
+        Self::emit_event("tournament_left", serde_json::json!({ "tournament_id": tournament_id, "account_id": player }));
+        Ok(())
+    }
+
+    /// Organizer can cancel a tournament that hasn't started, entry fees go back to the players' balances.
+    /// Storage of new balances is paid from the attached deposit or the organizer's balance.
+    #[payable]
+    #[handle_result]
+    pub fn cancel_tournament(&mut self, tournament_id: u32) -> Result<(), HopChainError> {
+        let mut tournament = self.get_tournament_state(tournament_id)?;
+        let organizer = env::predecessor_account_id();
+        if organizer != tournament.organizer {
+            return Err(HopChainError::Unauthorized);
+        }
+        if tournament.state != TournamentState::Registration {
+            return Err(HopChainError::WrongState);
+        }
+        let initial_storage = env::storage_usage();
+        for player in &tournament.players {
+            self.internal_deposit(&player.account_id, &None, tournament.entry_fee);
+        }
+        tournament.prize_pool = 0;
+        tournament.state = TournamentState::Cancelled;
+        self.tournaments.insert(&tournament_id, &tournament);
+        self.settle_storage(&organizer, initial_storage, env::attached_deposit())?;
+        Self::emit_event("tournament_cancelled", serde_json::json!({ "tournament_id": tournament_id }));
+        Ok(())
+    }
+
+    /// organizer closes the registration and the battles of the first round are created,
+    /// their storage is paid from the attached deposit or the organizer's balance
+    #[payable]
+    #[handle_result]
+    pub fn start_tournament(&mut self, tournament_id: u32) -> Result<(), HopChainError> {
+        self.assert_not_paused()?;
+        let mut tournament = self.get_tournament_state(tournament_id)?;
+        let organizer = env::predecessor_account_id();
+        if organizer != tournament.organizer {
+            return Err(HopChainError::Unauthorized);
+        }
+        if tournament.state != TournamentState::Registration || tournament.players.len() < 2 {
+            return Err(HopChainError::WrongState);
+        }
+        let initial_storage = env::storage_usage();
+        tournament.state = TournamentState::Running;
+        self.next_round(tournament_id, &mut tournament, &organizer)?;
+        self.tournaments.insert(&tournament_id, &tournament);
+        self.settle_storage(&organizer, initial_storage, env::attached_deposit())?;
+        Ok(())
+    }
+
+    /// Once every battle of the current round has ended, records the results and creates the battles
+    /// of the next round, or pays out the prize pool if the tournament is over. Anyone can call it,
+    /// storage of the new battles is paid from the attached deposit or the caller's balance and
+    /// goes back to the caller when they are pruned. With move timeouts disabled a battle whose player
+    /// stops moving never ends, the tournament waits until a moderator ends it with `resolve_battle`.
+    #[payable]
+    #[handle_result]
+    pub fn advance_tournament(&mut self, tournament_id: u32) -> Result<TournamentState, HopChainError> {
+        self.assert_not_paused()?;
+        let mut tournament = self.get_tournament_state(tournament_id)?;
+        if tournament.state != TournamentState::Running {
+            return Err(HopChainError::WrongState);
+        }
+        let mut results = vec![];
+        for pairing in &tournament.pairings {
+            results.push((*pairing, self.battle_result(pairing.battle_id).ok_or(HopChainError::WrongState)?));
+        }
+        for (pairing, winner) in results {
+            let (first, second) = (pairing.first as usize, pairing.second as usize);
+            let loser = match winner {
+                Some(winner) => {
+                    let (winner, loser) = if winner == tournament.players[second].account_id { (second, first) } else { (first, second) };
+                    tournament.players[winner].points += POINTS_FOR_WIN;
+                    loser
+                },
+                None => {
+                    tournament.players[first].points += POINTS_FOR_WIN / 2;
+                    tournament.players[second].points += POINTS_FOR_WIN / 2;
+                    second
+                }
+            };
+            if tournament.bracket == Bracket::SingleElimination && !tournament.players[loser].eliminated {
+                tournament.players[loser].eliminated = true;
+                tournament.players[loser].eliminated_in = tournament.round;
+            }
+        }
+        let caller = env::predecessor_account_id();
+        let initial_storage = env::storage_usage();
+        tournament.pairings.clear();
+        self.next_round(tournament_id, &mut tournament, &caller)?;
+        self.tournaments.insert(&tournament_id, &tournament);
+        self.settle_storage(&caller, initial_storage, env::attached_deposit())?;
+        Ok(tournament.state)
+    }
+
+    pub fn get_tournament(&self, tournament_id: u32) -> Option<TournamentView> {
+        self.tournaments.get(&tournament_id).map(|t| TournamentView::new(tournament_id, &t))
+    }
+}
+
+impl Contract {
+    fn get_tournament_state(&self, tournament_id: u32) -> Result<Tournament, HopChainError> {
+        self.tournaments.get(&tournament_id).ok_or(HopChainError::TournamentNotFound)
+    }
+
+    /// `None` while the battle is played, else its winner, `None` winner for a draw
+    fn battle_result(&self, battle_id: u32) -> Option<Option<AccountId>> {
+        if let Ok(battle) = self.get_battle(battle_id) {
+            return (battle.game.current_state >= 3).then_some(battle.game.winner);
+        }
+        self.archived_battles.get(&battle_id).map(|summary| summary.winner)
+    }
+
+    /// ends a tournament battle in favour of `winner`, tournament battles have no bid to pay out
+    fn forfeit_battle(&mut self, battle_id: u32, winner: &AccountId) -> Result<(), HopChainError> {
+        let mut battle = self.get_battle(battle_id)?;
+        if battle.game.current_state >= 3 {
+            return Ok(());
+        }
+        battle.game.winner = Some(winner.clone());
+        battle.game.current_state = 3;
+        battle.ended_at = Some(env::block_timestamp());
+        self.save_battle(&battle);
+        self.finish_battle(battle_id);
+        Ok(())
+    }
+
+    /// creates the battles of the next round or pays out the prize pool when the tournament is over,
+    /// `payer` pays the storage of the new battles and gets it back when they are pruned
+    fn next_round(&mut self, tournament_id: u32, tournament: &mut Tournament, payer: &AccountId) -> Result<(), HopChainError> {
+        if tournament.is_over() {
+            return self.finish_tournament(tournament_id, tournament);
+        }
+        tournament.round += 1;
+        for (first, second) in tournament.next_pairs() {
+            let first_player = tournament.players[first as usize].account_id.clone();
+            let second_player = tournament.players[second as usize].account_id.clone();
+            let battle_id = self.next_battle_id;
+            self.next_battle_id += 1;
+            let mut battle = Battle::new(0, None, first_player.clone(), battle_id, self.config.board_width, self.config.board_height)?;
+            battle.game.join(second_player.clone())?;
+            battle.last_action_at = env::block_timestamp();
+            battle.creation_payer = Some(payer.clone());
+            let initial_storage = env::storage_usage();
+            self.save_battle(&battle);
+            self.last_player_battle_id.insert(&first_player, &battle_id);
+            self.last_player_battle_id.insert(&second_player, &battle_id);
+            battle.creation_storage = env::storage_usage().saturating_sub(initial_storage);
+            self.save_battle(&battle);
+            tournament.played.push((first.min(second), first.max(second)));
+            tournament.pairings.push(Pairing { battle_id, first, second });
+        }
+        Self::emit_event("tournament_round_started", serde_json::json!({
+            "tournament_id": tournament_id,
+            "round": tournament.round,
+            "battles": tournament.pairings.iter().map(|p| p.battle_id).collect::<Vec<_>>()
+        }));
+        Ok(())
+    }
+
+    /// pays every place its share of the prize pool, shares of places nobody took and rounding go to the winner
+    fn finish_tournament(&mut self, tournament_id: u32, tournament: &mut Tournament) -> Result<(), HopChainError> {
+        let ranking = tournament.ranking();
+        tournament.standings = ranking.iter().map(|&i| tournament.players[i].account_id.clone()).collect();
+        let mut prizes: Vec<Balance> = tournament.payout.iter().take(ranking.len())
+            .map(|&share| tournament.prize_pool * share as Balance / MAX_FEE_BASIS_POINTS as Balance)
+            .collect();
+        prizes[0] += tournament.prize_pool - prizes.iter().sum::<Balance>();
+        // the entry fees are the players' own money, no platform fee is taken from the prizes
+        for (account_id, prize) in tournament.standings.clone().into_iter().zip(prizes) {
+            self.internal_deposit(&account_id, &None, prize);
+        }
+        tournament.state = TournamentState::Finished;
+        Self::emit_event("tournament_finished", serde_json::json!({
+            "tournament_id": tournament_id,
+            "standings": tournament.standings,
+            "prize_pool": U128(tournament.prize_pool)
+        }));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{account, call, json_keys, resolve, schema_properties};
+    use near_sdk::{testing_env, ONE_NEAR};
+
+    /// tournament of `players` registered in this order, entry fee is 1 NEAR
+    fn tournament(contract: &mut Contract, bracket: Bracket, payout: Vec<u16>, players: &[&str]) -> u32 {
+        testing_env!(call("organizer.near", ONE_NEAR).build());
+        let tournament_id = contract.create_tournament(U128(ONE_NEAR), 8, bracket, payout).unwrap();
+        for player in players {
+            testing_env!(call(player, 2 * ONE_NEAR).build());
+            contract.register_for_tournament(tournament_id).unwrap();
+        }
+        testing_env!(call("organizer.near", ONE_NEAR).build());
+        contract.start_tournament(tournament_id).unwrap();
+        tournament_id
+    }
+
+    fn advance(contract: &mut Contract, tournament_id: u32) -> TournamentView {
+        testing_env!(call("carol.near", ONE_NEAR).build());
+        contract.advance_tournament(tournament_id).unwrap();
+        contract.get_tournament(tournament_id).unwrap()
+    }
+
+    #[test]
+    fn single_elimination_handles_byes_and_forfeits() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        testing_env!(call("organizer.near", ONE_NEAR).build());
+        assert_eq!(contract.create_tournament(U128(ONE_NEAR), 8, Bracket::SingleElimination, vec![7000, 2000]).err(), Some(HopChainError::InvalidConfig));
+        let tournament_id = tournament(&mut contract, Bracket::SingleElimination, vec![7000, 3000], &["alice.near", "bob.near", "carol.near"]);
+
+        // alice is the best seed and skips the first round
+        let view = contract.get_tournament(tournament_id).unwrap();
+        assert_eq!((view.round, view.battles.len(), view.prize_pool), (1, 1, U128(3 * ONE_NEAR)));
+        assert_eq!(view.players[0].points, 2);
+        let battle = contract.get_battle(view.battles[0]).unwrap();
+        assert_eq!((battle.game.first_player, battle.game.second_player, battle.bid), (Some(account("bob.near")), Some(account("carol.near")), 0));
+        testing_env!(call("carol.near", 0).build());
+        assert_eq!(contract.advance_tournament(tournament_id).err(), Some(HopChainError::WrongState));
+
+        resolve(&mut contract, view.battles[0], BattleOutcome::SecondPlayerWins);
+        let view = advance(&mut contract, tournament_id);
+        assert_eq!(view.round, 2);
+        assert!(view.players[1].eliminated);
+        let final_battle = view.battles[0];
+
+        testing_env!(call("carol.near", 0).build());
+        contract.leave_tournament(tournament_id).unwrap();
+        assert_eq!(contract.get_battle(final_battle).unwrap().game.winner, Some(account("alice.near")));
+        let balances = |contract: &Contract| ["alice.near", "carol.near"].map(|a| contract.get_balance(account(a), None).0);
+        let before = balances(&contract);
+        let view = advance(&mut contract, tournament_id);
+        assert_eq!(view.state, TournamentState::Finished);
+        assert_eq!(view.standings, vec![account("alice.near"), account("carol.near"), account("bob.near")]);
+        let after = balances(&contract);
+        assert_eq!(after[0] - before[0], 21 * ONE_NEAR / 10);
+        assert!(after[1] - before[1] >= 9 * ONE_NEAR / 10);
+    }
+
+    #[test]
+    fn swiss_pairs_players_with_equal_points() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        let players = ["alice.near", "bob.near", "dave.near", "erin.near"];
+        let tournament_id = tournament(&mut contract, Bracket::Swiss { rounds: 2 }, vec![10000], &players);
+        let view = contract.get_tournament(tournament_id).unwrap();
+        resolve(&mut contract, view.battles[0], BattleOutcome::FirstPlayerWins);
+        resolve(&mut contract, view.battles[1], BattleOutcome::SecondPlayerWins);
+
+        // winners alice and erin meet in the second round
+        let view = advance(&mut contract, tournament_id);
+        let battle = contract.get_battle(view.battles[0]).unwrap();
+        assert_eq!((battle.game.first_player, battle.game.second_player), (Some(account("alice.near")), Some(account("erin.near"))));
+        resolve(&mut contract, view.battles[0], BattleOutcome::FirstPlayerWins);
+        resolve(&mut contract, view.battles[1], BattleOutcome::Draw);
+
+        let view = advance(&mut contract, tournament_id);
+        assert_eq!(view.state, TournamentState::Finished);
+        assert_eq!(view.players.iter().map(|p| p.points).collect::<Vec<_>>(), vec![4, 1, 1, 2]);
+        assert_eq!(view.standings[..2], [account("alice.near"), account("erin.near")]);
+        let json = serde_json::to_value(&view).unwrap();
+        assert_eq!(json_keys(&json), schema_properties("TournamentView"));
+        assert_eq!(json_keys(&json["players"][0]), schema_properties("TournamentPlayerView"));
+        assert_eq!(json["bracket"], serde_json::json!({ "Swiss": { "rounds": 2 } }));
+    }
+
+    #[test]
+    fn swiss_players_leave_before_and_during_the_tournament() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        testing_env!(call("organizer.near", ONE_NEAR).build());
+        let tournament_id = contract.create_tournament(U128(ONE_NEAR), 8, Bracket::Swiss { rounds: 2 }, vec![10000]).unwrap();
+        for player in ["alice.near", "bob.near", "dave.near", "erin.near"] {
+            testing_env!(call(player, 2 * ONE_NEAR).build());
+            contract.register_for_tournament(tournament_id).unwrap();
+        }
+        // leaving before the start returns the entry fee
+        let balance = contract.get_balance(account("erin.near"), None).0;
+        testing_env!(call("erin.near", 0).build());
+        contract.leave_tournament(tournament_id).unwrap();
+        assert!(contract.get_balance(account("erin.near"), None).0 - balance >= ONE_NEAR);
+        assert_eq!(contract.leave_tournament(tournament_id).err(), Some(HopChainError::Unauthorized));
+        testing_env!(call("organizer.near", ONE_NEAR).build());
+        contract.start_tournament(tournament_id).unwrap();
+
+        // dave is the lowest ranked of three and gets the bye
+        let view = contract.get_tournament(tournament_id).unwrap();
+        assert_eq!((view.players.len(), view.prize_pool, view.battles.len()), (3, U128(3 * ONE_NEAR), 1));
+        assert_eq!(view.players.iter().map(|p| p.points).collect::<Vec<_>>(), vec![0, 0, 2]);
+        // bob forfeits the running battle and isn't paired again
+        testing_env!(call("bob.near", 0).build());
+        contract.leave_tournament(tournament_id).unwrap();
+        assert_eq!(contract.leave_tournament(tournament_id).err(), Some(HopChainError::WrongState));
+        assert_eq!(contract.get_battle(view.battles[0]).unwrap().game.winner, Some(account("alice.near")));
+
+        let view = advance(&mut contract, tournament_id);
+        let battle = contract.get_battle(view.battles[0]).unwrap();
+        assert_eq!((view.round, view.battles.len()), (2, 1));
+        assert_eq!((battle.game.first_player, battle.game.second_player), (Some(account("alice.near")), Some(account("dave.near"))));
+        resolve(&mut contract, view.battles[0], BattleOutcome::SecondPlayerWins);
+        let view = advance(&mut contract, tournament_id);
+        assert_eq!(view.state, TournamentState::Finished);
+        assert_eq!(view.standings, vec![account("dave.near"), account("alice.near"), account("bob.near")]);
+    }
+
+    #[test]
+    fn tournament_battle_storage_goes_back_to_whoever_created_the_round() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 0);
+        let tournament_id = tournament(&mut contract, Bracket::Swiss { rounds: 2 }, vec![10000], &["alice.near", "bob.near"]);
+        let first_round = contract.get_tournament(tournament_id).unwrap().battles[0];
+        let battle = contract.get_battle(first_round).unwrap();
+        assert_eq!(battle.creation_payer, Some(account("organizer.near")));
+        assert!(battle.creation_storage > 0 && battle.first_player_storage == 0);
+        resolve(&mut contract, first_round, BattleOutcome::Draw);
+        let second_round = advance(&mut contract, tournament_id).battles[0];
+        assert_eq!(contract.get_battle(second_round).unwrap().creation_payer, Some(account("carol.near")));
+        resolve(&mut contract, second_round, BattleOutcome::FirstPlayerWins);
+        advance(&mut contract, tournament_id);
+
+        let balances = |contract: &Contract| ["organizer.near", "carol.near"].map(|a| contract.get_balance(account(a), None).0);
+        let before = balances(&contract);
+        testing_env!(call("dave.near", 0).build());
+        assert_eq!(contract.prune(10).unwrap(), 2);
+        let after = balances(&contract);
+        assert!(after[0] > before[0] && after[1] > before[1]);
+    }
+
+    #[test]
+    fn prizes_are_paid_without_the_platform_fee() {
+        testing_env!(call("owner.near", 0).build());
+        let mut contract = Contract::new(account("owner.near"), 500);
+        let tournament_id = tournament(&mut contract, Bracket::Swiss { rounds: 1 }, vec![10000], &["alice.near", "bob.near"]);
+        let battle_id = contract.get_tournament(tournament_id).unwrap().battles[0];
+        resolve(&mut contract, battle_id, BattleOutcome::FirstPlayerWins);
+        let balance = contract.get_balance(account("alice.near"), None).0;
+        assert_eq!(advance(&mut contract, tournament_id).state, TournamentState::Finished);
+        assert_eq!(contract.get_balance(account("alice.near"), None).0 - balance, 2 * ONE_NEAR);
+        assert_eq!(contract.get_treasury(None), U128(0));
+    }
+}